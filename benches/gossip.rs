@@ -0,0 +1,78 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use gossip_peer::agent::{Addr, Agent, Info, Message, Record};
+use gossip_peer::topology::RandomFanout;
+
+const TIME: u64 = 1_000_000_000;
+
+/// Counts allocations while enabled, to report how many a round makes next to its timings.
+struct Counting;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::Relaxed) {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocations(name: &str, agent: &mut Agent) {
+    // the first round allocates per-peer stats entries
+    agent.gossip(TIME);
+    COUNT.store(0, Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    let messages = agent.gossip(TIME);
+    COUNTING.store(false, Ordering::Relaxed);
+    drop(messages);
+    println!(
+        "{}: {} allocations per round",
+        name,
+        COUNT.load(Ordering::Relaxed)
+    );
+}
+
+fn addr(i: usize) -> Addr {
+    Addr::new(0x0a000000 + i as u32, 12000)
+}
+
+fn agent(n: usize) -> Agent {
+    let this = Record::new(addr(0), TIME, TIME);
+    let mut agent = Agent::new(this, vec![], 1000, 5000);
+    let list = (1..n).map(|i| Info::new(addr(i), TIME)).collect();
+    agent.accept(&Message::List(list), TIME);
+    agent
+}
+
+fn gossip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gossip");
+    for n in [10, 60, 500] {
+        let mut full = agent(n);
+        allocations(&format!("gossip/full_mesh/{}", n), &mut full);
+        group.bench_with_input(BenchmarkId::new("full_mesh", n), &n, |b, _| {
+            b.iter(|| black_box(full.gossip(TIME)))
+        });
+        let mut fanout = agent(n).with_topology(RandomFanout::seeded(3, 42));
+        allocations(&format!("gossip/fanout_3/{}", n), &mut fanout);
+        group.bench_with_input(BenchmarkId::new("fanout_3", n), &n, |b, _| {
+            b.iter(|| black_box(fanout.gossip(TIME)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, gossip);
+criterion_main!(benches);
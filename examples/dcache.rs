@@ -0,0 +1,93 @@
+//! A toy sharded in-memory cache on top of the membership agent.
+//!
+//! Three nodes run in one process on the virtual clock of `gossip_peer::testkit`. Every key is
+//! owned by the live member with the highest rendezvous hash for that key
+//! (`Agent::pick_owner`), so all nodes route a key to the same owner. When a node stops, the
+//! survivors detect it and its keys move.
+//!
+//! `cargo run --example dcache`
+
+use std::collections::HashMap;
+
+use gossip_peer::agent::Addr;
+use gossip_peer::testkit::Cluster;
+
+const PING_CUTOFF: u64 = 100;
+const FAIL_CUTOFF: u64 = 500;
+
+#[derive(Default)]
+struct Node {
+    stopped: bool,
+    cache: HashMap<String, String>,
+    seen: usize,
+}
+
+fn run(cluster: &mut Cluster, nodes: &mut [Node], millis: u64) {
+    let until = cluster.time() + millis;
+    while cluster.time() < until {
+        cluster.step();
+        for (i, node) in nodes
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, node)| !node.stopped)
+        {
+            for event in &cluster.events(i)[node.seen..] {
+                println!("{:?}: {:?}", cluster.addr(i), event);
+            }
+            node.seen = cluster.events(i).len();
+        }
+    }
+}
+
+fn owner(cluster: &Cluster, i: usize, key: &str) -> Addr {
+    cluster
+        .agent(i)
+        .pick_owner(key)
+        .expect("an agent always owns keys itself")
+        .addr()
+}
+
+fn put(cluster: &Cluster, nodes: &mut [Node], key: String, value: String) {
+    let live: Vec<usize> = (0..nodes.len()).filter(|&i| !nodes[i].stopped).collect();
+    let owner = owner(cluster, live[0], &key);
+    assert!(live.iter().all(|&i| self::owner(cluster, i, &key) == owner));
+    let i = live
+        .into_iter()
+        .find(|&i| cluster.addr(i) == owner)
+        .expect("owner is a live node");
+    nodes[i].cache.insert(key, value);
+}
+
+fn show(cluster: &Cluster, nodes: &[Node]) {
+    for (i, node) in nodes.iter().enumerate().filter(|(_, node)| !node.stopped) {
+        let mut keys: Vec<&String> = node.cache.keys().collect();
+        keys.sort();
+        println!("{:?} owns {:?}", cluster.addr(i), keys);
+    }
+}
+
+fn main() {
+    let mut cluster = Cluster::new(3, PING_CUTOFF, FAIL_CUTOFF);
+    let mut nodes: Vec<Node> = (0..3).map(|_| Node::default()).collect();
+
+    run(&mut cluster, &mut nodes, 1000);
+    for i in 0..12 {
+        put(
+            &cluster,
+            &mut nodes,
+            format!("key-{}", i),
+            format!("value-{}", i),
+        );
+    }
+    show(&cluster, &nodes);
+
+    println!("stopping {:?}", cluster.addr(2));
+    cluster.crash(2);
+    nodes[2].stopped = true;
+    run(&mut cluster, &mut nodes, 1000);
+
+    for (key, value) in std::mem::take(&mut nodes[2].cache) {
+        put(&cluster, &mut nodes, key, value);
+    }
+    show(&cluster, &nodes);
+}
@@ -1,8 +1,17 @@
+use std::collections::VecDeque;
 use std::fmt::{Debug, Error, Formatter};
 use std::net::{IpAddr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use smallvec::SmallVec;
+
+use crate::snapshot::Snapshots;
+use crate::stats::Stats;
+use crate::topology::{FullMesh, Topology};
+use crate::wire::{self, Decode, Encode};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Info {
@@ -10,6 +19,20 @@ pub struct Info {
     beat: u64,
 }
 
+impl Info {
+    pub fn new(addr: Addr, beat: u64) -> Self {
+        Self { addr, beat }
+    }
+
+    pub fn addr(&self) -> Addr {
+        self.addr
+    }
+
+    pub fn beat(&self) -> u64 {
+        self.beat
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Record {
     info: Info,
@@ -30,15 +53,193 @@ impl Record {
         self.info
     }
 
+    pub fn addr(&self) -> Addr {
+        self.info.addr
+    }
+
+    pub fn state(&self) -> State {
+        if self.is_down() {
+            State::Down
+        } else {
+            State::Alive
+        }
+    }
+
+    /// Millis since this member was last heard from, directly or through gossip.
+    pub fn age(&self, now: u64) -> u64 {
+        now.saturating_sub(self.time)
+    }
+
     fn is_down(&self) -> bool {
         self.down > 0
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// A member as seen by the agent that returned it, so it knows whether it is that agent's own
+/// node. There is no `metadata()`: the protocol carries only address and heartbeat for each
+/// member, and adding metadata would change the wire format.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Member {
+    record: Record,
+    this: bool,
+}
+
+impl Member {
+    pub fn addr(&self) -> Addr {
+        self.record.addr()
+    }
+
+    pub fn beat(&self) -> u64 {
+        self.record.info.beat
+    }
+
+    pub fn state(&self) -> State {
+        self.record.state()
+    }
+
+    /// Millis since this member was last heard from, directly or through gossip.
+    pub fn age(&self, now: u64) -> u64 {
+        self.record.age(now)
+    }
+
+    /// Whether this is the node of the agent that returned it.
+    pub fn is_self(&self) -> bool {
+        self.this
+    }
+
+    pub fn record(&self) -> Record {
+        self.record
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum State {
+    Alive,
+    Down,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Event {
     Append(Record),
     Remove(Record),
+    Rejoin { previous: Record, current: Record },
+    PossiblePartition { visible: usize, expected: usize },
+    PartitionHealed(Vec<Record>),
+    Unsafe,
+    Safe,
+    Reject(Addr),
+    TransportDown,
+    TransportUp,
+    HighChurn { changes: usize },
+    ChurnSubsided,
+    /// A peer runs with different cutoffs, so the two may disagree on when a member has failed.
+    Incompatible {
+        addr: Addr,
+        ping_cutoff: u64,
+        fail_cutoff: u64,
+    },
+    /// A peer's clock is estimated to be `skew` millis ahead of ours (behind if negative) by
+    /// more than the threshold given to `with_skew_detection`.
+    ClockSkew { addr: Addr, skew: i64 },
+    /// `Agent::leader` is now `leader`.
+    LeaderChanged { leader: Addr },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Append(_) => "append",
+            Event::Remove(_) => "remove",
+            Event::Rejoin { .. } => "rejoin",
+            Event::PossiblePartition { .. } => "possible_partition",
+            Event::PartitionHealed(_) => "partition_healed",
+            Event::Unsafe => "unsafe",
+            Event::Safe => "safe",
+            Event::Reject(_) => "reject",
+            Event::TransportDown => "transport_down",
+            Event::TransportUp => "transport_up",
+            Event::HighChurn { .. } => "high_churn",
+            Event::ChurnSubsided => "churn_subsided",
+            Event::Incompatible { .. } => "incompatible",
+            Event::ClockSkew { .. } => "clock_skew",
+            Event::LeaderChanged { .. } => "leader_changed",
+        }
+    }
+}
+
+/// Why the last gossip round went the way it did, recorded when decision tracing is enabled.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Trace {
+    pub time: u64,
+    /// Peers the topology picked to gossip to.
+    pub targets: Vec<Addr>,
+    /// Live, recent peers the topology did not pick.
+    pub unselected: Vec<Addr>,
+    /// Peers skipped because they are marked down.
+    pub down: Vec<Addr>,
+    /// Peers skipped because they have not been heard from within the ping cutoff.
+    pub quiet: Vec<Addr>,
+    /// Entries listed in the round, this node included and hidden members left out.
+    pub included: Vec<Addr>,
+}
+
+struct Approval(Box<dyn Fn(&Addr) -> bool + Send>);
+
+impl Debug for Approval {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("Approval")
+    }
+}
+
+struct Priority(Box<dyn Fn(&Record) -> i64 + Send>);
+
+impl Debug for Priority {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("Priority")
+    }
+}
+
+#[derive(Debug)]
+struct Partition {
+    threshold: f64,
+    period: u64,
+    since: Option<u64>,
+    alerted: bool,
+}
+
+const CHURN_WINDOW: u64 = 60000;
+
+/// Partition detection periods after which a membership that stayed low is taken as the new
+/// expected size, e.g. once nodes were decommissioned.
+const PEAK_DECAY_PERIODS: u64 = 10;
+
+/// How often `take_params` announces this node's parameters to every live peer.
+pub const PARAMS_INTERVAL: u64 = 60000;
+
+/// Most peers remembered as incompatible; the oldest is forgotten beyond that.
+const MAX_INCOMPATIBLE: usize = 256;
+
+/// Most peers a clock skew estimate is kept for; the oldest is forgotten beyond that.
+const MAX_SKEWS: usize = 256;
+
+#[derive(Debug)]
+struct Skew {
+    addr: Addr,
+    millis: i64,
+    flagged: bool,
+}
+
+/// Most rejected peers remembered; beyond that the oldest is forgotten and asked about again
+/// if it shows up.
+const MAX_REJECTED: usize = 256;
+
+#[derive(Debug)]
+struct Churn {
+    threshold: usize,
+    stretch: u64,
+    changes: VecDeque<u64>,
+    high: bool,
+    joining_until: u64,
 }
 
 #[derive(Debug)]
@@ -48,6 +249,32 @@ pub struct Agent {
     peers: Vec<Record>,
     ping_cutoff: u64,
     fail_cutoff: u64,
+    peak: usize,
+    partition: Option<Partition>,
+    churn: Option<Churn>,
+    warm_up_until: u64,
+    heal_threshold: Option<usize>,
+    sync: bool,
+    safety: bool,
+    safe: bool,
+    fixed: bool,
+    approval: Option<Approval>,
+    priority: Option<Priority>,
+    rejected: Vec<Addr>,
+    hidden: Vec<Addr>,
+    incompatible: Vec<Addr>,
+    skews: Vec<Skew>,
+    skew_threshold: Option<u64>,
+    introduce: Vec<Addr>,
+    last_params: u64,
+    trace: Option<Trace>,
+    stats: Stats,
+    reclaim: Option<u64>,
+    topology: Box<dyn Topology>,
+    snapshots: Snapshots,
+    names: Vec<(Addr, String)>,
+    local_hosts: Vec<u32>,
+    leader: Addr,
 }
 
 impl Agent {
@@ -58,11 +285,238 @@ impl Agent {
             peers: vec![],
             ping_cutoff,
             fail_cutoff,
+            peak: 1,
+            partition: None,
+            churn: None,
+            warm_up_until: 0,
+            heal_threshold: None,
+            sync: false,
+            safety: false,
+            safe: true,
+            fixed: false,
+            approval: None,
+            priority: None,
+            rejected: vec![],
+            hidden: vec![],
+            incompatible: vec![],
+            skews: vec![],
+            skew_threshold: None,
+            introduce: vec![],
+            last_params: 0,
+            trace: None,
+            stats: Stats::default(),
+            reclaim: None,
+            topology: Box::new(FullMesh),
+            snapshots: Snapshots::new(vec![this.info.addr]),
+            names: vec![],
+            local_hosts: vec![],
+            leader: this.info.addr,
         }
     }
 
+    /// Alert when visible/peak membership stays below `threshold` for `period` millis. The peak
+    /// drops to the visible membership once it has stayed below for `PEAK_DECAY_PERIODS`
+    /// periods, and never exceeds the members known, so reclaimed members are not expected.
+    pub fn with_partition_detection(mut self, threshold: f64, period: u64) -> Agent {
+        self.partition = Some(Partition {
+            threshold,
+            period,
+            since: None,
+            alerted: false,
+        });
+        self
+    }
+
+    /// Emit `Event::HighChurn` once members join, leave or rejoin at least `threshold` times
+    /// within a minute, and multiply the fail cutoff by `stretch` until churn subsides, so that
+    /// peers slowed down by a rolling restart are not declared failed on top of it. Members
+    /// appended during this node's first ping and fail cutoff, while it joins, are not counted.
+    pub fn with_churn_detection(mut self, threshold: usize, stretch: u64) -> Agent {
+        self.churn = Some(Churn {
+            threshold,
+            stretch,
+            changes: VecDeque::new(),
+            high: false,
+            joining_until: self.this.time + self.ping_cutoff + self.fail_cutoff,
+        });
+        self
+    }
+
+    /// Declare no peer failed until `duration` after this node's start, while it learns the
+    /// current membership, so stale peers it starts with do not turn into a storm of removals.
+    pub fn with_warm_up(mut self, duration: u64) -> Agent {
+        self.warm_up_until = self.this.time + duration;
+        self
+    }
+
+    /// Emit `Event::ClockSkew` once a peer's clock is estimated to be more than `threshold`
+    /// millis off, see `skew`.
+    pub fn with_skew_detection(mut self, threshold: u64) -> Agent {
+        self.skew_threshold = Some(threshold);
+        self
+    }
+
+    /// Emit `Event::PartitionHealed` when at least `threshold` removed members reappear at once.
+    pub fn with_heal_detection(mut self, threshold: usize) -> Agent {
+        self.heal_threshold = Some(threshold);
+        self
+    }
+
+    /// Mark this node unsafe (not ready) while it has lost contact with every known peer.
+    pub fn with_safety_mode(mut self) -> Agent {
+        self.safety = true;
+        self
+    }
+
+    /// Only seeds are members: peers learned from gossip and unknown senders are ignored.
+    pub fn with_static_peers(mut self) -> Agent {
+        self.fixed = true;
+        self
+    }
+
+    /// Track and gossip with these members but never list them to other peers, e.g. monitoring
+    /// nodes that must not be contacted by the rest of the cluster. Works only if every node that
+    /// hears from them is configured the same.
+    pub fn with_hidden_members(mut self, hidden: Vec<Addr>) -> Agent {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Record a `Trace` of every gossip round, see `last_trace`.
+    pub fn with_decision_trace(mut self) -> Agent {
+        self.trace = Some(Trace::default());
+        self
+    }
+
+    /// Ask `approve` before admitting a new peer; rejected peers are ignored from then on (the
+    /// last `MAX_REJECTED` of them, older ones are asked about again).
+    pub fn with_join_approval<F>(mut self, approve: F) -> Agent
+    where
+        F: Fn(&Addr) -> bool + Send + 'static,
+    {
+        self.approval = Some(Approval(Box::new(approve)));
+        self
+    }
+
+    /// Prefer members with a lower `priority` (e.g. 0 for SSD-backed nodes, 1 for the rest) in
+    /// `members_by_priority`, `pick_owner`, `pick_replicas` and sampling. As long as `priority`
+    /// depends only on the member, every node prefers the same members.
+    pub fn with_member_priority<F>(mut self, priority: F) -> Agent
+    where
+        F: Fn(&Record) -> i64 + Send + 'static,
+    {
+        self.priority = Some(Priority(Box::new(priority)));
+        self
+    }
+
+    /// Forget removed peers entirely once they have been down for `timeout` millis.
+    pub fn with_dead_member_reclaim(mut self, timeout: u64) -> Agent {
+        self.reclaim = Some(timeout);
+        self
+    }
+
+    /// Addresses of this node's interfaces. While its own host is 0, peers list it by the
+    /// address they received it from, and an entry with this node's port and one of these hosts
+    /// (or a loopback host) is taken for this node instead of a new peer. Behind NAT the address
+    /// peers see is not local; configure the host instead.
+    pub fn with_local_hosts(mut self, hosts: Vec<u32>) -> Agent {
+        self.local_hosts = hosts;
+        self
+    }
+
+    pub fn with_topology<T: Topology + 'static>(mut self, topology: T) -> Agent {
+        self.topology = Box::new(topology);
+        self
+    }
+
+    /// Replace the seeds, e.g. after their names resolved to new addresses.
+    pub fn set_seeds(&mut self, seeds: Vec<Addr>) {
+        self.seeds = seeds;
+    }
+
+    /// Change the cutoffs at runtime, e.g. to keep them in step with a slower gossip schedule.
+    /// `params` reports the new values from then on.
+    pub fn set_cutoffs(&mut self, ping_cutoff: u64, fail_cutoff: u64) {
+        self.ping_cutoff = ping_cutoff;
+        self.fail_cutoff = fail_cutoff;
+    }
+
+    /// Names members were configured as, e.g. seed host names, to look up with `name` and in
+    /// snapshots. Republishes the snapshot.
+    pub fn set_names(&mut self, names: Vec<(Addr, String)>) {
+        self.names = names;
+        self.publish();
+    }
+
+    pub fn name(&self, addr: &Addr) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(a, _)| a == addr)
+            .map(|(_, name)| name.as_str())
+    }
+
+    pub fn is_allowed(&self, addr: &Addr) -> bool {
+        (!self.fixed || self.seeds.contains(addr)) && !self.rejected.contains(addr)
+    }
+
     pub fn is_ready(&self) -> bool {
-        !self.peers.is_empty()
+        !self.peers.is_empty() && self.safe
+    }
+
+    pub fn info(&self) -> Info {
+        self.this.info
+    }
+
+    /// This node's timing parameters, to send along with pings so that peers configured with
+    /// other cutoffs are reported as `Event::Incompatible`. `time` is the send time peers
+    /// estimate clock skew from, so it should come from the clock given to every other call.
+    pub fn params(&self, time: u64) -> Message {
+        Message::Params {
+            ping_cutoff: self.ping_cutoff,
+            fail_cutoff: self.fail_cutoff,
+            time,
+        }
+    }
+
+    /// How far the peer's clock is estimated to be ahead of ours in millis (behind if
+    /// negative), from the send times of the params it sent: a moving average of the send time
+    /// minus the receive time, so it includes the one-way network delay. `None` until the peer
+    /// sent params.
+    pub fn skew(&self, addr: &Addr) -> Option<i64> {
+        self.skews
+            .iter()
+            .find(|skew| &skew.addr == addr)
+            .map(|skew| skew.millis)
+    }
+
+    pub fn is_self(&self, record: &Record) -> bool {
+        self.is_this(&record.info.addr)
+    }
+
+    /// The member at `addr` if it is known, down or alive, this node included.
+    pub fn member(&self, addr: &Addr) -> Option<Member> {
+        if self.is_this(addr) {
+            return Some(self.as_member(self.this));
+        }
+        self.peers
+            .iter()
+            .find(|p| &p.info.addr == addr)
+            .map(|record| self.as_member(*record))
+    }
+
+    fn as_member(&self, record: Record) -> Member {
+        Member {
+            record,
+            this: self.is_self(&record),
+        }
+    }
+
+    fn is_this(&self, addr: &Addr) -> bool {
+        let this = self.this.info.addr;
+        if this.host != 0 || addr.port != this.port {
+            return *addr == this;
+        }
+        addr.host == 0 || addr.host >> 24 == 127 || self.local_hosts.contains(&addr.host)
     }
 
     pub fn tick(&mut self, time: u64) {
@@ -70,8 +524,9 @@ impl Agent {
         self.this.time = time;
     }
 
-    pub fn ping(&self) -> Vec<&Addr> {
-        self.seeds
+    pub fn ping(&mut self) -> Vec<Addr> {
+        let addrs: Vec<Addr> = self
+            .seeds
             .iter()
             .filter(|peer| {
                 self.peers
@@ -79,209 +534,809 @@ impl Agent {
                     .filter(|p| !p.is_down())
                     .all(|p| &p.info.addr != *peer)
             })
-            .collect()
+            .cloned()
+            .collect();
+        let ping = Message::Ping(self.this.info);
+        for addr in &addrs {
+            self.stats.on_send(*addr, &ping);
+        }
+        addrs
     }
 
-    fn get_mut(&mut self, addr: &Addr) -> Option<&mut Record> {
-        self.peers.iter_mut().find(|rec| &rec.info.addr == addr)
+    /// This node followed by live peers, in the order they were first seen.
+    pub fn members(&self) -> Vec<Record> {
+        let mut members = vec![self.this];
+        members.extend(self.peers.iter().filter(|p| !p.is_down()));
+        members
     }
 
-    pub fn detect(&mut self, time: u64) -> Vec<Event> {
-        let total_cutoff = self.ping_cutoff + self.fail_cutoff;
-        self.peers
-            .iter_mut()
-            .filter(|record| !record.is_down())
-            .filter(|record| record.time <= time - total_cutoff)
-            .map(|record| {
-                record.down = time;
-                Event::Remove(*record)
-            })
-            .collect()
+    /// Up to `n` live peers, this node excluded, chosen uniformly at random (in no particular
+    /// order) by reservoir sampling. With `with_member_priority`, peers of a lower priority
+    /// are chosen before any of a higher one.
+    pub fn sample(&self, n: usize) -> Vec<Record> {
+        self.sample_with(n, &mut rand::thread_rng())
     }
 
-    pub fn accept(&mut self, message: &Message, time: u64) -> Vec<Event> {
-        let mut events = self.detect(time);
-        match message {
-            Message::Ping(peer) => {
-                if let Some(event) = self.touch(peer, time) {
-                    events.push(event);
-                }
-            }
-            Message::List(list) => {
-                list.iter()
-                    .filter_map(|received| self.touch(received, time))
-                    .for_each(|event| events.push(event));
-            }
+    pub fn sample_with<R: Rng + ?Sized>(&self, n: usize, rng: &mut R) -> Vec<Record> {
+        let live = self.peers.iter().filter(|p| !p.is_down()).cloned();
+        if self.priority.is_none() {
+            return live.choose_multiple(rng, n);
         }
-        events
+        let mut live: Vec<Record> = live.collect();
+        live.shuffle(rng);
+        live.sort_by_key(|record| self.priority(record));
+        live.truncate(n);
+        live
     }
 
-    fn touch(&mut self, info: &Info, time: u64) -> Option<Event> {
-        if let Some(record) = self.get_mut(&info.addr) {
-            let is_ping = info.beat == 0 && record.is_down();
-            if info.beat > record.info.beat || is_ping {
-                record.info.beat = info.beat;
-                record.time = time;
-                record.down = 0;
-            }
-            if is_ping {
-                Some(Event::Append(*record))
-            } else {
-                None
-            }
-        } else {
-            let record = Record {
-                info: *info,
-                time,
-                down: 0,
-            };
-            self.peers.push(record);
-            Some(Event::Append(record))
-        }
+    /// Like `sample`, with each peer's chance proportional to `weight`, e.g. derived from
+    /// latency or health the caller measures. Peers weighing zero or less are never chosen, and
+    /// member priority applies as in `sample`.
+    pub fn sample_weighted<F>(&self, n: usize, weight: F) -> Vec<Record>
+    where
+        F: Fn(&Record) -> f64,
+    {
+        self.sample_weighted_with(n, weight, &mut rand::thread_rng())
     }
 
-    pub fn gossip(&mut self, time: u64) -> Vec<(Addr, Message)> {
-        let mut peers: Vec<Record> = self
+    pub fn sample_weighted_with<F, R>(&self, n: usize, weight: F, rng: &mut R) -> Vec<Record>
+    where
+        F: Fn(&Record) -> f64,
+        R: Rng + ?Sized,
+    {
+        // Efraimidis-Spirakis: keep the n largest keys u^(1/w), u uniform in [0, 1)
+        let mut keyed: Vec<(f64, Record)> = self
             .peers
-            .clone()
-            .into_iter()
-            .filter(|record| !record.is_down())
-            .filter(|record| record.time > time - self.ping_cutoff)
-            .collect();
-        peers.push(self.this);
-
-        self.peers
             .iter()
-            .filter(|record| !record.is_down())
-            .filter(|record| record.time > time - self.ping_cutoff)
-            .map(|record| {
-                let selected = peers
-                    .clone()
-                    .into_iter()
-                    .map(|r| r.info)
-                    .filter(|info| info.addr != record.info.addr)
-                    .collect();
-                (record.info.addr, Message::List(selected))
+            .filter(|p| !p.is_down())
+            .filter_map(|record| {
+                let w = weight(record);
+                if w > 0.0 {
+                    Some((rng.gen::<f64>().powf(1.0 / w), *record))
+                } else {
+                    None
+                }
             })
-            .collect()
+            .collect();
+        keyed.sort_by(|a, b| {
+            let (x, y) = (self.priority(&a.1), self.priority(&b.1));
+            x.cmp(&y).then(b.0.total_cmp(&a.0))
+        });
+        keyed.into_iter().take(n).map(|(_, record)| record).collect()
     }
-}
 
-#[derive(Copy, Clone, Eq, PartialEq)]
-pub struct Addr {
-    pub host: u32,
-    pub port: u16,
-}
+    /// Live members ordered by address (host, then port), this node included. Nodes agree on
+    /// the result only if each is configured with the address its peers see it by.
+    pub fn members_sorted(&self) -> Vec<Record> {
+        let mut members = self.members();
+        members.sort_by_key(|record| record.info.addr);
+        members
+    }
 
-impl Addr {
-    pub fn addr(&self) -> SocketAddr {
-        SocketAddr::from((self.host.to_be_bytes(), self.port))
+    /// Live members, this node included, ordered by an application-defined priority `key`
+    /// (lowest first) with ties broken by address. As long as `key` depends only on the member,
+    /// every node computes the same order.
+    pub fn members_by<K, F>(&self, key: F) -> Vec<Record>
+    where
+        K: Ord,
+        F: Fn(&Record) -> K,
+    {
+        let mut members = self.members();
+        members.sort_by(|a, b| key(a).cmp(&key(b)).then(a.info.addr.cmp(&b.info.addr)));
+        members
     }
-}
 
-impl Debug for Addr {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        let addr = format!("{}", self.addr());
-        f.write_str(addr.as_str()).expect("failed to format Addr");
-        Ok(())
+    /// `members_by` the priority given to `with_member_priority`, e.g. to elect the first one
+    /// as leader; ordered by address without one.
+    pub fn members_by_priority(&self) -> Vec<Record> {
+        self.members_by(|record| self.priority(record))
     }
-}
 
-impl From<SocketAddr> for Addr {
-    fn from(addr: SocketAddr) -> Self {
-        Self {
-            host: match addr.ip() {
-                IpAddr::V4(ip) => ip.into(),
-                _ => panic!("IPv6 is not unsupported"),
-            },
-            port: addr.port(),
-        }
+    /// The first of `members_by_priority`: this node while it knows no peers. Nodes that agree
+    /// on membership agree on the leader, so work that must run on one node only can be gated
+    /// on `is_leader` (see `leader::Leadership`). As with `members_sorted`, each node must be
+    /// configured with the address its peers see it by.
+    pub fn leader(&self) -> Member {
+        self.as_member(self.members_by_priority()[0])
     }
-}
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum Message {
-    Ping(Info),
-    List(Vec<Info>),
-}
+    pub fn is_leader(&self) -> bool {
+        self.leader().is_self()
+    }
 
-impl Message {
-    pub fn patch(&mut self, ip: Addr) {
-        match self {
-            Message::Ping(info) => {
-                info.addr.host = ip.host;
-            }
-            Message::List(list) => {
-                for info in list {
-                    if info.addr.host == 0 {
-                        info.addr.host = ip.host;
-                    }
-                }
-            }
-        }
+    /// The live member that owns `key`: the one with the highest rendezvous hash of key and
+    /// address. Nodes that agree on membership agree on the owner, and when a member leaves
+    /// only its own keys move. As with `members_sorted`, nodes agree only if each is configured
+    /// with the address its peers see it by, not a 0 host. With `with_member_priority`, the
+    /// owner is among the members of the lowest priority.
+    ///
+    /// `None` only without live members. The agent counts its own node as live, so it owns
+    /// every key while it knows no live peers (`Member::is_self`), e.g. when it starts alone.
+    pub fn pick_owner<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Option<Member> {
+        self.pick_replicas(key, 1).into_iter().next()
     }
 
-    pub fn bytes(&self) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(128);
-        match self {
-            Message::Ping(from) => {
-                buf.put_u8(0);
-                buf.put_u32(from.addr.host);
-                buf.put_u16(from.addr.port);
-                buf.put_u64(from.beat);
-            }
-            Message::List(list) => {
-                buf.put_u8(1);
-                buf.put_u32(list.len() as u32);
-                for info in list {
-                    buf.put_u32(info.addr.host);
-                    buf.put_u16(info.addr.port);
-                    buf.put_u64(info.beat);
+    /// Up to `n` live members for `key`, owner first, in the same rendezvous order within each
+    /// priority, lowest priority first.
+    pub fn pick_replicas<K: AsRef<[u8]> + ?Sized>(&self, key: &K, n: usize) -> Vec<Member> {
+        let key = key.as_ref();
+        let mut scored: Vec<(u64, Record)> = self
+            .members()
+            .into_iter()
+            .map(|record| (rendezvous(key, &record.info.addr), record))
+            .collect();
+        scored.sort_by(|a, b| {
+            let (x, y) = (self.priority(&a.1), self.priority(&b.1));
+            x.cmp(&y)
+                .then(b.0.cmp(&a.0))
+                .then(a.1.info.addr.cmp(&b.1.info.addr))
+        });
+        scored
+            .into_iter()
+            .take(n)
+            .map(|(_, record)| self.as_member(record))
+            .collect()
+    }
+
+    /// Handle to membership snapshots, republished whenever a member joins, leaves or rejoins.
+    pub fn snapshots(&self) -> Snapshots {
+        self.snapshots.clone()
+    }
+
+    /// The last gossip round's decisions, if tracing is enabled.
+    pub fn last_trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.alive = self.peers.iter().filter(|p| !p.is_down()).count();
+        stats.down = self.peers.len() - stats.alive;
+        stats
+    }
+
+    fn count(&mut self, events: &[Event]) {
+        for event in events {
+            *self.stats.events.entry(event.kind()).or_insert(0) += 1;
+            if let Event::Append(record) | Event::Rejoin { current: record, .. } = event {
+                if !self.introduce.contains(&record.info.addr) {
+                    self.introduce.push(record.info.addr);
                 }
             }
         }
-        buf.to_vec()
+        let changed = events.iter().any(|event| {
+            matches!(
+                event,
+                Event::Append(_) | Event::Remove(_) | Event::Rejoin { .. }
+            )
+        });
+        if changed {
+            self.publish();
+        }
     }
 
-    pub fn parse(buf: &[u8]) -> Option<Message> {
-        let mut bb = Bytes::copy_from_slice(buf);
-        let code = bb.get_u8();
-        match code {
-            0 /* Ping */ => {
-                let host = bb.get_u32();
-                let port = bb.get_u16();
-                let beat = bb.get_u64();
-                let info = Info { addr: Addr {host, port}, beat };
-                Some(Message::Ping(info))
-            },
-            1 /* List */ => {
-                let count = bb.get_u32() as usize;
-                let mut infos = Vec::with_capacity(count);
-                for _ in 0..count {
-                    let host = bb.get_u32();
-                    let port = bb.get_u16();
-                    let beat = bb.get_u64();
-                    let info = Info { addr: Addr {host, port}, beat };
-                    infos.push(info);
-                }
-                Some(Message::List(infos))
-            },
-            _ => None
+    fn publish(&self) {
+        let members = self.members_sorted();
+        self.snapshots.publish(
+            members.iter().map(|record| record.info.addr).collect(),
+            self.names.clone(),
+        );
+    }
+
+    /// `params` messages to send: to peers that joined or rejoined since the last call, and to
+    /// every live peer once per `PARAMS_INTERVAL`, so mismatched cutoffs are noticed on both
+    /// sides of any pair of members, not only by seeds.
+    pub fn take_params(&mut self, time: u64) -> Vec<(Addr, Message)> {
+        let mut addrs = std::mem::take(&mut self.introduce);
+        if time.saturating_sub(self.last_params) >= PARAMS_INTERVAL {
+            self.last_params = time;
+            addrs = self
+                .peers
+                .iter()
+                .filter(|record| !record.is_down())
+                .map(|record| record.info.addr)
+                .collect();
+        }
+        let params = self.params(time);
+        let messages: Vec<(Addr, Message)> = addrs
+            .into_iter()
+            .filter(|addr| self.get(addr).is_some_and(|record| !record.is_down()))
+            .map(|addr| (addr, params.clone()))
+            .collect();
+        for (addr, message) in &messages {
+            self.stats.on_send(*addr, message);
         }
+        messages
     }
-}
 
-pub fn get_current_millis() -> u64 {
-    let now = SystemTime::now();
-    let epoch = now
-        .duration_since(UNIX_EPOCH)
-        .expect("Failed to get unix epoch time");
-    epoch.as_millis() as u64
+    pub fn take_sync(&mut self) -> bool {
+        std::mem::replace(&mut self.sync, false)
+    }
+
+    fn get(&self, addr: &Addr) -> Option<&Record> {
+        self.peers.iter().find(|rec| &rec.info.addr == addr)
+    }
+
+    fn get_mut(&mut self, addr: &Addr) -> Option<&mut Record> {
+        self.peers.iter_mut().find(|rec| &rec.info.addr == addr)
+    }
+
+    pub fn detect(&mut self, time: u64) -> Vec<Event> {
+        let mut events = self.expire(time);
+        if let Some(event) = self.check_churn(&events, time) {
+            events.push(event);
+        }
+        if let Some(event) = self.check_leader() {
+            events.push(event);
+        }
+        self.count(&events);
+        events
+    }
+
+    fn expire(&mut self, time: u64) -> Vec<Event> {
+        let stretch = match &self.churn {
+            Some(churn) if churn.high => churn.stretch,
+            _ => 1,
+        };
+        let total_cutoff = self.ping_cutoff + self.fail_cutoff * stretch;
+        let warm = time >= self.warm_up_until;
+        let mut events: Vec<Event> = self
+            .peers
+            .iter_mut()
+            .filter(|record| warm && !record.is_down())
+            .filter(|record| record.time <= time - total_cutoff)
+            .map(|record| {
+                record.down = time;
+                Event::Remove(*record)
+            })
+            .collect();
+        if let Some(reclaim) = self.reclaim {
+            let stats = &mut self.stats;
+            self.peers.retain(|record| {
+                let keep = !record.is_down() || time - record.down < reclaim;
+                if !keep {
+                    stats.peers.remove(&record.info.addr);
+                }
+                keep
+            });
+        }
+        if let Some(event) = self.check_partition(time) {
+            events.push(event);
+        }
+        if let Some(event) = self.check_safety() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn check_safety(&mut self) -> Option<Event> {
+        if !self.safety || self.peers.is_empty() {
+            return None;
+        }
+        let safe = self.peers.iter().any(|p| !p.is_down());
+        if safe == self.safe {
+            return None;
+        }
+        self.safe = safe;
+        Some(if safe { Event::Safe } else { Event::Unsafe })
+    }
+
+    fn check_churn(&mut self, events: &[Event], time: u64) -> Option<Event> {
+        let churn = self.churn.as_mut()?;
+        let joining = time < churn.joining_until;
+        for event in events {
+            match event {
+                Event::Append(_) if joining => (),
+                Event::Append(_) | Event::Remove(_) | Event::Rejoin { .. } => {
+                    churn.changes.push_back(time)
+                }
+                _ => (),
+            }
+        }
+        while churn.changes.front().is_some_and(|t| time - t >= CHURN_WINDOW) {
+            churn.changes.pop_front();
+        }
+        let changes = churn.changes.len();
+        match (churn.high, changes >= churn.threshold) {
+            (false, true) => {
+                churn.high = true;
+                Some(Event::HighChurn { changes })
+            }
+            (true, false) => {
+                churn.high = false;
+                Some(Event::ChurnSubsided)
+            }
+            _ => None,
+        }
+    }
+
+    fn check_leader(&mut self) -> Option<Event> {
+        let leader = self.leader().addr();
+        if leader == self.leader {
+            return None;
+        }
+        self.leader = leader;
+        Some(Event::LeaderChanged { leader })
+    }
+
+    fn check_partition(&mut self, time: u64) -> Option<Event> {
+        let visible = self.peers.iter().filter(|p| !p.is_down()).count() + 1;
+        self.peak = self.peak.min(self.peers.len() + 1).max(visible);
+        let partition = self.partition.as_mut()?;
+        if partition
+            .since
+            .is_some_and(|since| time - since >= PEAK_DECAY_PERIODS * partition.period)
+        {
+            self.peak = visible;
+        }
+        let expected = self.peak;
+        if visible as f64 >= partition.threshold * expected as f64 {
+            partition.since = None;
+            partition.alerted = false;
+            return None;
+        }
+        let since = *partition.since.get_or_insert(time);
+        if partition.alerted || time - since < partition.period {
+            return None;
+        }
+        partition.alerted = true;
+        Some(Event::PossiblePartition { visible, expected })
+    }
+
+    /// Same as `accept`, additionally patching hosts missing from the message and accounting
+    /// it to the peer it was received from.
+    pub fn accept_from(&mut self, from: Addr, message: &Message, time: u64) -> Vec<Event> {
+        let mut message = message.clone();
+        message.patch(from);
+        self.receive(Some(from), &message, time)
+    }
+
+    /// Add peers the caller already knows about, e.g. from its own inventory, so that they are
+    /// gossiped to right away instead of being discovered through the seeds. They are admitted
+    /// like any new peer and expire as usual if they never answer.
+    pub fn add_known_peers(&mut self, addrs: Vec<Addr>, time: u64) -> Vec<Event> {
+        let mut events: Vec<Event> = addrs
+            .iter()
+            .filter_map(|addr| self.touch(&Info::new(*addr, 0), false, time))
+            .collect();
+        if let Some(event) = self.check_safety() {
+            events.push(event);
+        }
+        if let Some(event) = self.check_churn(&events, time) {
+            events.push(event);
+        }
+        if let Some(event) = self.check_leader() {
+            events.push(event);
+        }
+        self.count(&events);
+        events
+    }
+
+    pub fn accept(&mut self, message: &Message, time: u64) -> Vec<Event> {
+        self.receive(None, message, time)
+    }
+
+    fn receive(&mut self, from: Option<Addr>, message: &Message, time: u64) -> Vec<Event> {
+        self.stats.on_receive(from, message);
+        let mut events = self.expire(time);
+        match message {
+            Message::Ping(peer) => {
+                if let Some(event) = self.touch(peer, true, time) {
+                    events.push(event);
+                }
+            }
+            Message::List(list) => {
+                let mut healed = Vec::new();
+                for received in list {
+                    if let Some(event) = self.touch(received, false, time) {
+                        if let Event::Rejoin { current, .. } = &event {
+                            healed.push(*current);
+                        }
+                        events.push(event);
+                    }
+                }
+                match self.heal_threshold {
+                    Some(threshold) if !healed.is_empty() && healed.len() >= threshold => {
+                        events.push(Event::PartitionHealed(healed));
+                        self.sync = true;
+                    }
+                    _ => (),
+                }
+            }
+            Message::Echo(_) | Message::EchoReply(_) => (),
+            Message::Params {
+                ping_cutoff,
+                fail_cutoff,
+                time: sent,
+            } => {
+                if let Some(addr) = from {
+                    events.extend(self.check_params(addr, *ping_cutoff, *fail_cutoff));
+                    events.extend(self.check_skew(addr, *sent, time));
+                }
+            }
+        }
+        if let Some(event) = self.check_safety() {
+            events.push(event);
+        }
+        if let Some(event) = self.check_churn(&events, time) {
+            events.push(event);
+        }
+        if let Some(event) = self.check_leader() {
+            events.push(event);
+        }
+        self.count(&events);
+        events
+    }
+
+    /// Reports a peer once while its skew stays above the threshold, and again only after it
+    /// has dropped below.
+    fn check_skew(&mut self, addr: Addr, sent: u64, time: u64) -> Option<Event> {
+        let sample = sent as i64 - time as i64;
+        let skew = match self.skews.iter_mut().position(|skew| skew.addr == addr) {
+            Some(at) => {
+                let skew = &mut self.skews[at];
+                skew.millis += (sample - skew.millis) / 4;
+                skew
+            }
+            None => {
+                if self.skews.len() >= MAX_SKEWS {
+                    self.skews.remove(0);
+                }
+                self.skews.push(Skew {
+                    addr,
+                    millis: sample,
+                    flagged: false,
+                });
+                self.skews.last_mut().unwrap()
+            }
+        };
+        let high = skew.millis.unsigned_abs() > self.skew_threshold?;
+        let report = high && !skew.flagged;
+        skew.flagged = high;
+        if report {
+            Some(Event::ClockSkew {
+                addr,
+                skew: skew.millis,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Reports a peer once per mismatch, and again only after it has been seen compatible.
+    fn check_params(&mut self, addr: Addr, ping_cutoff: u64, fail_cutoff: u64) -> Option<Event> {
+        let flagged = self.incompatible.contains(&addr);
+        if ping_cutoff == self.ping_cutoff && fail_cutoff == self.fail_cutoff {
+            self.incompatible.retain(|a| a != &addr);
+            return None;
+        }
+        if flagged {
+            return None;
+        }
+        if self.incompatible.len() >= MAX_INCOMPATIBLE {
+            self.incompatible.remove(0);
+        }
+        self.incompatible.push(addr);
+        Some(Event::Incompatible {
+            addr,
+            ping_cutoff,
+            fail_cutoff,
+        })
+    }
+
+    /// `direct` is set for a ping from the member itself, the only source for which a 0 beat
+    /// means a restart rather than a placeholder from `add_known_peers`.
+    fn touch(&mut self, info: &Info, direct: bool, time: u64) -> Option<Event> {
+        if self.is_this(&info.addr) || !self.is_allowed(&info.addr) {
+            return None;
+        }
+        // Entries still missing a host after patching, or with no port, cannot be reached
+        if info.addr.host == 0 || info.addr.port == 0 {
+            return None;
+        }
+        if let Some(record) = self.get_mut(&info.addr) {
+            let previous = *record;
+            let is_ping = direct && info.beat == 0 && previous.is_down();
+            if info.beat > record.info.beat || is_ping {
+                record.info.beat = info.beat;
+                record.time = time;
+                record.down = 0;
+            }
+            if previous.is_down() && !record.is_down() {
+                Some(Event::Rejoin {
+                    previous,
+                    current: *record,
+                })
+            } else {
+                None
+            }
+        } else if !self.approve(&info.addr) {
+            if self.rejected.len() >= MAX_REJECTED {
+                self.rejected.remove(0);
+            }
+            self.rejected.push(info.addr);
+            Some(Event::Reject(info.addr))
+        } else {
+            let record = Record {
+                info: *info,
+                time,
+                down: 0,
+            };
+            self.peers.push(record);
+            Some(Event::Append(record))
+        }
+    }
+
+    fn priority(&self, record: &Record) -> i64 {
+        self.priority
+            .as_ref()
+            .map(|priority| (priority.0)(record))
+            .unwrap_or(0)
+    }
+
+    fn approve(&self, addr: &Addr) -> bool {
+        self.approval
+            .as_ref()
+            .map(|approval| (approval.0)(addr))
+            .unwrap_or(true)
+    }
+
+    /// A gossip round to every live peer, bypassing the topology, for moments when convergence
+    /// matters more than traffic (e.g. right after a deploy).
+    pub fn force_gossip_round(&mut self, time: u64) -> Vec<(Addr, Message)> {
+        let topology = std::mem::replace(&mut self.topology, Box::new(FullMesh));
+        let messages = self.gossip(time);
+        self.topology = topology;
+        messages
+    }
+
+    /// A ping for `addr` outside the seed ping schedule, e.g. to check on a suspect peer or to
+    /// announce this node to one that may have forgotten it.
+    pub fn force_probe(&mut self, addr: Addr) -> Message {
+        let ping = Message::Ping(self.this.info);
+        self.stats.on_send(addr, &ping);
+        ping
+    }
+
+    /// Declare `addr` failed now, as if its fail cutoff had passed, e.g. on an out-of-band
+    /// report that it is gone, or to test the reaction to it. It rejoins as soon as it is heard
+    /// from with a newer beat.
+    pub fn force_remove(&mut self, addr: &Addr, time: u64) -> Vec<Event> {
+        let mut events: Vec<Event> = self
+            .get_mut(addr)
+            .filter(|record| !record.is_down())
+            .map(|record| {
+                record.down = time;
+                Event::Remove(*record)
+            })
+            .into_iter()
+            .collect();
+        if let Some(event) = self.check_safety() {
+            events.push(event);
+        }
+        if let Some(event) = self.check_churn(&events, time) {
+            events.push(event);
+        }
+        if let Some(event) = self.check_leader() {
+            events.push(event);
+        }
+        self.count(&events);
+        events
+    }
+
+    pub fn gossip(&mut self, time: u64) -> Vec<(Addr, Message)> {
+        let cutoff = time - self.ping_cutoff;
+        let mut infos = InfoList::with_capacity(self.peers.len() + 1);
+        infos.extend(
+            self.peers
+                .iter()
+                .filter(|record| !record.is_down() && record.time > cutoff)
+                .map(|record| record.info),
+        );
+
+        let mut candidates: Vec<Addr> = infos.iter().map(|info| info.addr).collect();
+        candidates.sort();
+        let targets = self.topology.select(self.this.info.addr, &candidates);
+        // Placeholders from add_known_peers are gossiped to but not listed until heard from
+        infos.retain(|info| info.beat > 0 && !self.hidden.contains(&info.addr));
+        infos.push(self.this.info);
+        if self.trace.is_some() {
+            self.trace = Some(self.trace_round(time, &candidates, &targets, &infos));
+        }
+
+        let mut messages: Vec<(Addr, Message)> = Vec::with_capacity(targets.len());
+        for target in targets {
+            let mut selected = InfoList::with_capacity(infos.len());
+            selected.extend(infos.iter().filter(|info| info.addr != target).copied());
+            if selected.len() <= MAX_LIST_LEN {
+                messages.push((target, Message::List(selected)));
+            } else {
+                messages.extend(
+                    selected
+                        .chunks(MAX_LIST_LEN)
+                        .map(|chunk| (target, Message::List(InfoList::from_slice(chunk)))),
+                );
+            }
+        }
+
+        self.stats.rounds += 1;
+        for (addr, message) in &messages {
+            self.stats.on_send(*addr, message);
+        }
+        messages
+    }
+
+    fn trace_round(
+        &self,
+        time: u64,
+        candidates: &[Addr],
+        targets: &[Addr],
+        infos: &[Info],
+    ) -> Trace {
+        let cutoff = time - self.ping_cutoff;
+        let skipped = |down: bool| {
+            self.peers
+                .iter()
+                .filter(|record| record.is_down() == down && (down || record.time <= cutoff))
+                .map(|record| record.info.addr)
+                .collect()
+        };
+        Trace {
+            time,
+            targets: targets.to_vec(),
+            unselected: candidates
+                .iter()
+                .filter(|addr| !targets.contains(addr))
+                .copied()
+                .collect(),
+            down: skipped(true),
+            quiet: skipped(false),
+            included: infos.iter().map(|info| info.addr).collect(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Addr {
+    host: u32,
+    port: u16,
+}
+
+impl Addr {
+    /// An IPv4 address, `host` in host byte order, e.g. `0x7f000001` for 127.0.0.1.
+    pub fn new(host: u32, port: u16) -> Self {
+        Self { host, port }
+    }
+
+    pub fn host(&self) -> u32 {
+        self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::from((self.host.to_be_bytes(), self.port))
+    }
+}
+
+impl Debug for Addr {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let addr = format!("{}", self.addr());
+        f.write_str(addr.as_str()).expect("failed to format Addr");
+        Ok(())
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            host: match addr.ip() {
+                IpAddr::V4(ip) => ip.into(),
+                _ => panic!("IPv6 is not unsupported"),
+            },
+            port: addr.port(),
+        }
+    }
+}
+
+pub const MAX_DATAGRAM: usize = 1024;
+
+/// Lists of up to this many entries, a small cluster's membership, are kept off the heap.
+/// Inline entries make every `Message` larger, so this stays small.
+pub const INLINE_LIST_LEN: usize = 8;
+
+pub type InfoList = SmallVec<[Info; INLINE_LIST_LEN]>;
+
+const MAX_LIST_LEN: usize = (MAX_DATAGRAM - wire::CODE_LEN - wire::COUNT_LEN) / wire::INFO_LEN;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    Ping(Info),
+    List(InfoList),
+    Echo(u64),
+    EchoReply(u64),
+    Params {
+        ping_cutoff: u64,
+        fail_cutoff: u64,
+        time: u64,
+    },
+}
+
+impl Message {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "ping",
+            Message::List(_) => "list",
+            Message::Echo(_) => "echo",
+            Message::EchoReply(_) => "echo_reply",
+            Message::Params { .. } => "params",
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        wire::len(self)
+    }
+
+    pub fn patch(&mut self, ip: Addr) {
+        let infos = match self {
+            Message::Ping(info) => std::slice::from_mut(info),
+            Message::List(list) => list.as_mut_slice(),
+            Message::Echo(_) | Message::EchoReply(_) | Message::Params { .. } => &mut [],
+        };
+        for info in infos {
+            if info.addr.host == 0 {
+                info.addr.host = ip.host;
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(self.size());
+        self.encode(&mut buf);
+        buf.to_vec()
+    }
+
+    pub fn parse(buf: &[u8]) -> Option<Message> {
+        Message::decode(&mut Bytes::copy_from_slice(buf))
+    }
+}
+
+/// FNV-1a over the key and the big-endian address, then the MurmurHash3 finalizer to spread
+/// addresses that differ in a few bits. Fully specified, so every build on every platform
+/// computes the same score.
+fn rendezvous(key: &[u8], addr: &Addr) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let host = addr.host.to_be_bytes();
+    let port = addr.port.to_be_bytes();
+    for byte in key.iter().chain(&host).chain(&port) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+pub fn get_current_millis() -> u64 {
+    let now = SystemTime::now();
+    let epoch = now
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get unix epoch time");
+    epoch.as_millis() as u64
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::topology::{RandomFanout, StaticNeighbors};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use smallvec::smallvec;
+    use std::collections::{HashMap, HashSet};
 
     const PING_CUTOFF: u64 = 1000;
     const FAIL_CUTOFF: u64 = 5000;
@@ -321,10 +1376,1082 @@ mod tests {
         assert!(agent.detect(time).is_empty());
         assert_eq!(
             agent.gossip(time),
-            vec![(addr(2), Message::List(vec![info(1, 101)]))]
+            vec![(addr(2), Message::List(smallvec![info(1, 101)]))]
         );
 
         time += PING_CUTOFF;
         assert!(agent.gossip(time).is_empty());
     }
+
+    #[test]
+    fn test_partition() {
+        let mut time = 1000000000;
+        let period = FAIL_CUTOFF;
+
+        let mut agent = agent(1, time, 101).with_partition_detection(0.5, period);
+        for i in 2..=4 {
+            agent.accept(&Message::Ping(info(i, 101)), time);
+        }
+        time += PING_CUTOFF / 2;
+        agent.accept(&Message::List(smallvec![info(2, 102)]), time);
+
+        // peers 3 and 4 drop out: 2 out of 4 members visible is still not below the threshold
+        time += PING_CUTOFF + FAIL_CUTOFF - PING_CUTOFF / 2;
+        assert_eq!(agent.detect(time).len(), 2);
+
+        // peer 2 drops out too: 1 out of 4 members visible, alert only after the period
+        time += PING_CUTOFF / 2;
+        assert_eq!(agent.detect(time).len(), 1);
+        assert!(agent.detect(time + period - 1).is_empty());
+        assert_eq!(
+            agent.detect(time + period),
+            vec![Event::PossiblePartition {
+                visible: 1,
+                expected: 4
+            }]
+        );
+        assert!(agent.detect(time + period + 1).is_empty());
+    }
+
+    #[test]
+    fn test_partition_peak_decay() {
+        let mut time = 1000000000;
+        let period = FAIL_CUTOFF;
+
+        let mut agent = agent(1, time, 101).with_partition_detection(0.5, period);
+        for i in 2..=6 {
+            agent.accept(&Message::Ping(info(i, 101)), time);
+        }
+
+        // 4 out of 6 members decommissioned
+        time += FAIL_CUTOFF;
+        agent.accept(&Message::List(smallvec![info(2, 102)]), time);
+        time += PING_CUTOFF;
+        assert_eq!(agent.detect(time).len(), 4);
+
+        // after long enough the smaller cluster is what is expected
+        let mut events = vec![];
+        let mut beat = 102;
+        let until = time + PEAK_DECAY_PERIODS * period;
+        while time < until {
+            time += PING_CUTOFF;
+            beat += 1;
+            events.extend(agent.accept(&Message::List(smallvec![info(2, beat)]), time));
+            events.extend(agent.detect(time));
+        }
+        assert_eq!(
+            events,
+            vec![Event::PossiblePartition {
+                visible: 2,
+                expected: 6
+            }]
+        );
+        assert_eq!(agent.peak, 2);
+    }
+
+    #[test]
+    fn test_partition_peak_reclaim() {
+        let mut time = 1000000000;
+        let reclaim = 10 * FAIL_CUTOFF;
+
+        let mut agent = agent(1, time, 101)
+            .with_partition_detection(0.5, 100 * FAIL_CUTOFF)
+            .with_dead_member_reclaim(reclaim);
+        for i in 2..=4 {
+            agent.accept(&Message::Ping(info(i, 101)), time);
+        }
+        assert!(agent.detect(time).is_empty());
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        assert_eq!(agent.detect(time).len(), 3);
+        assert_eq!(agent.peak, 4);
+        assert!(agent.detect(time + reclaim).is_empty());
+        assert_eq!(agent.peak, 1);
+    }
+
+    #[test]
+    fn test_partition_healed() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_heal_detection(2);
+        for i in 2..=4 {
+            agent.accept(&Message::Ping(info(i, 101)), time);
+        }
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        assert_eq!(agent.detect(time).len(), 3);
+        assert!(!agent.take_sync());
+
+        let down = time;
+        time += PING_CUTOFF;
+        let events = agent.accept(&Message::List(smallvec![info(2, 110), info(3, 110)]), time);
+        let healed = vec![
+            Record::new(addr(2), time, 110),
+            Record::new(addr(3), time, 110),
+        ];
+        let previous = |i| Record {
+            info: info(i, 101),
+            time: down - PING_CUTOFF - FAIL_CUTOFF,
+            down,
+        };
+        assert_eq!(
+            events,
+            vec![
+                Event::Rejoin {
+                    previous: previous(2),
+                    current: healed[0]
+                },
+                Event::Rejoin {
+                    previous: previous(3),
+                    current: healed[1]
+                },
+                Event::PartitionHealed(healed),
+            ]
+        );
+        assert!(agent.take_sync());
+        assert!(!agent.take_sync());
+
+        let events = agent.accept(&Message::List(smallvec![info(4, 110)]), time);
+        assert_eq!(
+            events,
+            vec![Event::Rejoin {
+                previous: previous(4),
+                current: Record::new(addr(4), time, 110)
+            }]
+        );
+        assert!(!agent.take_sync());
+    }
+
+    #[test]
+    fn test_safety_mode() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_safety_mode();
+        assert!(!agent.is_ready());
+
+        agent.accept(&Message::Ping(info(2, 101)), time);
+        assert!(agent.is_ready());
+
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        let removed = Record {
+            info: info(2, 101),
+            time: time - PING_CUTOFF - FAIL_CUTOFF,
+            down: time,
+        };
+        assert_eq!(
+            agent.detect(time),
+            vec![Event::Remove(removed), Event::Unsafe]
+        );
+        assert!(!agent.is_ready());
+
+        time += PING_CUTOFF;
+        assert_eq!(
+            agent.accept(&Message::Ping(info(2, 0)), time),
+            vec![
+                Event::Rejoin {
+                    previous: removed,
+                    current: Record::new(addr(2), time, 0)
+                },
+                Event::Safe
+            ]
+        );
+        assert!(agent.is_ready());
+    }
+
+    #[test]
+    fn test_static_peers() {
+        let time = 1000000000;
+
+        let mut agent = Agent::new(
+            Record::new(addr(1), time, 101),
+            vec![addr(2)],
+            PING_CUTOFF,
+            FAIL_CUTOFF,
+        )
+        .with_static_peers();
+        assert!(agent.is_allowed(&addr(2)));
+        assert!(!agent.is_allowed(&addr(3)));
+
+        assert!(agent.accept(&Message::Ping(info(3, 101)), time).is_empty());
+        assert_eq!(
+            agent.accept(&Message::List(smallvec![info(2, 101), info(3, 101)]), time),
+            vec![Event::Append(Record::new(addr(2), time, 101))]
+        );
+        assert_eq!(agent.peers, vec![Record::new(addr(2), time, 101)]);
+    }
+
+    #[test]
+    fn test_join_approval() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_join_approval(|addr| addr.port % 2 == 0);
+        assert_eq!(
+            agent.accept(&Message::List(smallvec![info(2, 101), info(3, 101)]), time),
+            vec![
+                Event::Append(Record::new(addr(2), time, 101)),
+                Event::Reject(addr(3))
+            ]
+        );
+        assert!(!agent.is_allowed(&addr(3)));
+        assert!(agent.accept(&Message::Ping(info(3, 102)), time).is_empty());
+        assert_eq!(agent.peers, vec![Record::new(addr(2), time, 101)]);
+
+        for port in 0..MAX_REJECTED as u16 {
+            let from = Addr { host: 1, port: 2 * port + 1 };
+            agent.accept_from(from, &Message::Ping(Info { addr: from, beat: 1 }), time);
+        }
+        assert_eq!(agent.rejected.len(), MAX_REJECTED);
+        assert!(agent.is_allowed(&addr(3)));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let list = Message::List(smallvec![info(1, 101), info(2, 102)]);
+        let bytes = list.bytes();
+        assert_eq!(Message::parse(&bytes), Some(list));
+
+        assert_eq!(Message::parse(&[]), None);
+        assert_eq!(Message::parse(&[2]), None);
+        assert_eq!(Message::parse(&[0, 1, 2, 3]), None);
+        assert_eq!(Message::parse(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Message::parse(&[1, 0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(Message::parse(&[2, 0, 0, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_echo() {
+        for echo in &[Message::Echo(42), Message::EchoReply(u64::MAX)] {
+            let bytes = echo.bytes();
+            assert_eq!(bytes.len(), echo.size());
+            assert_eq!(Message::parse(&bytes).as_ref(), Some(echo));
+        }
+    }
+
+    #[test]
+    fn test_gossip_split() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        let list = (2..=200).map(|i| info(i, 101)).collect();
+        agent.accept(&Message::List(list), time);
+
+        let messages: Vec<Message> = agent
+            .gossip(time)
+            .into_iter()
+            .filter(|(to, _)| to == &addr(2))
+            .map(|(_, message)| message)
+            .collect();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|m| m.bytes().len() <= MAX_DATAGRAM));
+
+        let received: usize = messages
+            .iter()
+            .map(|m| match m {
+                Message::List(list) => list.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(received, 199);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut time = 1000000000;
+
+        let mut agent = Agent::new(
+            Record::new(addr(1), time, 101),
+            vec![addr(2), addr(3)],
+            PING_CUTOFF,
+            FAIL_CUTOFF,
+        );
+        assert_eq!(agent.ping().len(), 2);
+        agent.accept_from(addr(2), &Message::Ping(info(2, 101)), time);
+        agent.accept_from(addr(3), &Message::List(smallvec![info(3, 101), info(4, 101)]), time);
+        let messages = agent.gossip(time);
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        agent.detect(time);
+
+        let stats = agent.stats();
+        assert_eq!(stats.rounds, 1);
+        assert_eq!(stats.sent["ping"].count, 2);
+        assert_eq!(stats.sent["list"].count, messages.len() as u64);
+        assert_eq!(
+            stats.sent["list"].bytes,
+            messages.iter().map(|(_, m)| m.bytes().len() as u64).sum::<u64>()
+        );
+        assert_eq!(stats.received["ping"].count, 1);
+        assert_eq!(stats.received["list"].bytes, 5 + 2 * 14);
+        assert_eq!(stats.events["append"], 3);
+        assert_eq!(stats.events["remove"], 3);
+
+        let to_4: u64 = messages
+            .iter()
+            .filter(|(to, _)| to == &addr(4))
+            .map(|(_, m)| m.size() as u64)
+            .sum();
+        assert_eq!(stats.peers[&addr(2)].sent.count, 2);
+        assert_eq!(stats.peers[&addr(2)].received.bytes, 15);
+        assert_eq!(stats.peers[&addr(3)].received.bytes, 5 + 2 * 14);
+        assert_eq!(stats.peers[&addr(4)].received.count, 0);
+        assert_eq!(stats.peers[&addr(4)].sent.bytes, to_4);
+        assert_eq!((stats.alive, stats.down), (0, 3));
+    }
+
+    #[test]
+    fn test_dead_member_reclaim() {
+        let mut time = 1000000000;
+        let reclaim = 10 * FAIL_CUTOFF;
+
+        let mut agent = agent(1, time, 101).with_dead_member_reclaim(reclaim);
+        agent.accept(&Message::Ping(info(2, 101)), time);
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        assert_eq!(agent.detect(time).len(), 1);
+        assert_eq!(agent.peers.len(), 1);
+
+        assert!(agent.detect(time + reclaim - 1).is_empty());
+        assert_eq!(agent.peers.len(), 1);
+        assert!(agent.detect(time + reclaim).is_empty());
+        assert!(agent.peers.is_empty());
+    }
+
+    #[test]
+    fn test_members_sorted() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(3, time, 101);
+        agent.accept(&Message::List(smallvec![info(4, 101), info(1, 101)]), time);
+        time += PING_CUTOFF;
+        agent.accept(&Message::List(smallvec![info(2, 101), info(1, 102)]), time);
+        time += FAIL_CUTOFF;
+        agent.detect(time);
+
+        let addrs = |members: Vec<Record>| -> Vec<Addr> {
+            members.into_iter().map(|r| r.info.addr).collect()
+        };
+        assert_eq!(addrs(agent.members()), vec![addr(3), addr(1), addr(2)]);
+        assert_eq!(addrs(agent.members_sorted()), vec![addr(1), addr(2), addr(3)]);
+    }
+
+    #[test]
+    fn test_leader_changed() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(2, time, 101);
+        assert!(agent.is_leader());
+        let events = agent.accept(&Message::List(smallvec![info(3, 101)]), time);
+        assert!(!events.iter().any(|e| e.kind() == "leader_changed"));
+
+        let events = agent.accept(&Message::Ping(info(1, 101)), time);
+        assert!(events.contains(&Event::LeaderChanged { leader: addr(1) }));
+        assert!(!agent.is_leader());
+
+        time += PING_CUTOFF;
+        agent.accept(&Message::Ping(info(3, 102)), time);
+        time += FAIL_CUTOFF;
+        let events = agent.detect(time);
+        assert!(events.contains(&Event::LeaderChanged { leader: addr(2) }));
+        assert!(agent.is_leader());
+    }
+
+    #[test]
+    fn test_gossip_topology() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_topology(RandomFanout::seeded(2, 42));
+        let list = (2..=10).map(|i| info(i, 101)).collect();
+        agent.accept(&Message::List(list), time);
+
+        let messages = agent.gossip(time);
+        assert_eq!(messages.len(), 2);
+        assert_ne!(messages[0].0, messages[1].0);
+        for (to, message) in messages {
+            match message {
+                Message::List(list) => {
+                    assert_eq!(list.len(), 9);
+                    assert!(list.iter().all(|info| info.addr != to));
+                }
+                _ => panic!("unexpected message: {:?}", message),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ignore_self() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, time);
+        let list = smallvec![info(1, time - 100), info(1, time + 100), info(2, 101)];
+        assert_eq!(
+            agent.accept(&Message::List(list), time),
+            vec![Event::Append(Record::new(addr(2), time, 101))]
+        );
+        assert_eq!(
+            agent.members(),
+            vec![Record::new(addr(1), time, time), Record::new(addr(2), time, 101)]
+        );
+    }
+
+    #[test]
+    fn test_ignore_self_without_host() {
+        let time = 1000000000;
+
+        let this = Addr { host: 0, port: 1 };
+        let mut agent = Agent::new(Record::new(this, time, time), vec![], PING_CUTOFF, FAIL_CUTOFF)
+            .with_local_hosts(vec![addr(1).host]);
+        let loopback = Info::new(
+            Addr {
+                host: 0x7f000001,
+                port: 1,
+            },
+            time,
+        );
+        let other = Info::new(
+            Addr {
+                host: addr(2).host,
+                port: 1,
+            },
+            101,
+        );
+        let list = smallvec![info(1, time), loopback, other];
+        assert_eq!(
+            agent.accept(&Message::List(list), time),
+            vec![Event::Append(Record::new(other.addr(), time, 101))]
+        );
+        assert_eq!(agent.members().len(), 2);
+    }
+
+    #[test]
+    fn test_patch() {
+        let source = addr(9);
+        let unset = |i: u8, beat: u64| Info {
+            addr: Addr {
+                host: 0,
+                port: i as u16,
+            },
+            beat,
+        };
+
+        // NAT'd: the sender does not know its public address, the source fills it in
+        let mut message = Message::Ping(unset(2, 101));
+        message.patch(source);
+        assert_eq!(
+            message,
+            Message::Ping(Info {
+                addr: Addr {
+                    host: source.host,
+                    port: 2
+                },
+                beat: 101
+            })
+        );
+
+        // Advertised with `--host`: kept even though the packet came from another address
+        let mut message = Message::Ping(info(2, 101));
+        message.patch(source);
+        assert_eq!(message, Message::Ping(info(2, 101)));
+
+        // Relayed: entries already patched by the relaying peer are kept, its own is filled in
+        let mut message = Message::List(smallvec![unset(9, 101), info(2, 101), info(3, 101)]);
+        message.patch(source);
+        assert_eq!(
+            message,
+            Message::List(smallvec![info(9, 101), info(2, 101), info(3, 101)])
+        );
+
+        let time = 1000000000;
+        let mut agent = agent(1, time, 101);
+        assert_eq!(
+            agent.accept_from(source, &Message::Ping(unset(9, 101)), time),
+            vec![Event::Append(Record::new(source, time, 101))]
+        );
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(2, time, 101);
+        let snapshots = agent.snapshots();
+        assert_eq!(snapshots.current().members, vec![addr(2)]);
+
+        agent.accept(&Message::List(smallvec![info(3, 101), info(1, 101)]), time);
+        let snapshot = std::thread::spawn(move || snapshots.current())
+            .join()
+            .unwrap();
+        assert_eq!(snapshot.version, 1);
+        assert_eq!(snapshot.members, vec![addr(1), addr(2), addr(3)]);
+
+        time += PING_CUTOFF;
+        agent.tick(time);
+        agent.accept(&Message::Ping(info(3, 102)), time);
+        assert_eq!(agent.snapshots().current(), snapshot);
+
+        time += FAIL_CUTOFF;
+        agent.detect(time);
+        let current = agent.snapshots().current();
+        assert_eq!(current.version, 2);
+        assert_eq!(current.members, vec![addr(2), addr(3)]);
+
+        agent.set_names(vec![(addr(3), "node-3.internal:7946".to_string())]);
+        assert_eq!(agent.name(&addr(3)), Some("node-3.internal:7946"));
+        let current = agent.snapshots().current();
+        assert_eq!(current.version, 3);
+        assert_eq!(current.name(&addr(3)), Some("node-3.internal:7946"));
+        assert_eq!(current.name(&addr(2)), None);
+    }
+
+    #[test]
+    fn test_sample() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        assert!(agent.sample(3).is_empty());
+        let list = (2..=11).map(|i| info(i, 101)).collect();
+        agent.accept(&Message::List(list), time);
+        assert_eq!(agent.sample(20).len(), 10);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts: HashMap<Addr, usize> = HashMap::new();
+        let rounds = 30000;
+        for _ in 0..rounds {
+            let sample = agent.sample_with(3, &mut rng);
+            assert_eq!(sample.len(), 3);
+            let distinct: HashSet<Addr> = sample.iter().map(|r| r.info.addr).collect();
+            assert_eq!(distinct.len(), 3);
+            for record in sample {
+                *counts.entry(record.info.addr).or_insert(0) += 1;
+            }
+        }
+        // each of 10 peers is expected in 3/10 of the samples, allow 5% either way
+        let expected = rounds * 3 / 10;
+        assert_eq!(counts.len(), 10);
+        assert!(!counts.contains_key(&addr(1)));
+        for count in counts.values() {
+            assert!(count.abs_diff(expected) < expected / 20, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_sample_weighted() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        let list = (2..=4).map(|i| info(i, 101)).collect();
+        agent.accept(&Message::List(list), time);
+
+        let weight = |record: &Record| match record.info.addr.port {
+            2 => 3.0,
+            3 => 1.0,
+            _ => 0.0,
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts: HashMap<Addr, usize> = HashMap::new();
+        let rounds = 40000;
+        for _ in 0..rounds {
+            for record in agent.sample_weighted_with(1, weight, &mut rng) {
+                *counts.entry(record.info.addr).or_insert(0) += 1;
+            }
+        }
+        assert!(!counts.contains_key(&addr(4)));
+        let heavy = counts[&addr(2)];
+        assert_eq!(heavy + counts[&addr(3)], rounds);
+        assert!(heavy.abs_diff(rounds * 3 / 4) < rounds / 40, "{:?}", counts);
+
+        let sample = agent.sample_weighted_with(5, weight, &mut rng);
+        let mut addrs: Vec<Addr> = sample.iter().map(|r| r.info.addr).collect();
+        addrs.sort();
+        assert_eq!(addrs, vec![addr(2), addr(3)]);
+    }
+
+    #[test]
+    fn test_hidden_members() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_hidden_members(vec![addr(3)]);
+        agent.accept(&Message::List(smallvec![info(2, 101), info(3, 101)]), time);
+        assert_eq!(agent.members().len(), 3);
+
+        let mut messages = agent.gossip(time);
+        messages.sort_by_key(|(to, _)| *to);
+        assert_eq!(
+            messages,
+            vec![
+                (addr(2), Message::List(smallvec![info(1, 101)])),
+                (addr(3), Message::List(smallvec![info(2, 101), info(1, 101)])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_known_peers() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        agent.accept(&Message::Ping(info(2, 101)), time);
+        let events = agent.add_known_peers(vec![addr(1), addr(2), addr(3), addr(4)], time);
+        assert_eq!(
+            events,
+            vec![
+                Event::Append(Record::new(addr(3), time, 0)),
+                Event::Append(Record::new(addr(4), time, 0)),
+            ]
+        );
+        assert_eq!(agent.members().len(), 4);
+        let messages = agent.gossip(time);
+        assert_eq!(messages.len(), 3);
+        for (_, message) in messages {
+            match message {
+                Message::List(list) => assert!(list.iter().all(|info| info.beat > 0)),
+                _ => panic!("unexpected message: {:?}", message),
+            }
+        }
+
+        // heard from 3 only
+        time += PING_CUTOFF;
+        agent.accept(&Message::Ping(info(3, 1)), time);
+        time += FAIL_CUTOFF;
+        let removed: Vec<Addr> = agent
+            .detect(time)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Remove(record) => Some(record.info.addr),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(removed, vec![addr(2), addr(4)]);
+    }
+
+    #[test]
+    fn test_relayed_placeholder() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        agent.accept(&Message::Ping(info(4, 7)), time);
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        agent.detect(time);
+
+        // a 0 beat relayed in a list does not revive a member seen failing, a ping from it does
+        assert!(agent
+            .accept(&Message::List(smallvec![info(4, 0)]), time)
+            .is_empty());
+        assert!(matches!(
+            agent.accept(&Message::Ping(info(4, 0)), time).as_slice(),
+            [Event::Rejoin { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_force() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_topology(RandomFanout::seeded(1, 42));
+        let list = (2..=5).map(|i| info(i, 101)).collect();
+        agent.accept(&Message::List(list), time);
+
+        assert_eq!(agent.gossip(time).len(), 1);
+        let mut targets: Vec<Addr> = agent
+            .force_gossip_round(time)
+            .into_iter()
+            .map(|(to, _)| to)
+            .collect();
+        targets.sort();
+        assert_eq!(targets, vec![addr(2), addr(3), addr(4), addr(5)]);
+        assert_eq!(agent.gossip(time).len(), 1);
+
+        assert_eq!(agent.force_probe(addr(9)), Message::Ping(info(1, 101)));
+        assert_eq!(agent.stats().sent["ping"].count, 1);
+    }
+
+    #[test]
+    fn test_churn_detection() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_churn_detection(3, 2);
+        // members found while joining are not churn
+        let list = (5..=7).map(|i| info(i, 101)).collect();
+        assert_eq!(agent.accept(&Message::List(list), time).len(), 3);
+        for beat in 102..=103 {
+            time += FAIL_CUTOFF;
+            let list = (5..=7).map(|i| info(i, beat)).collect();
+            assert!(agent.accept(&Message::List(list), time).is_empty());
+        }
+        let list = (2..=4).map(|i| info(i, 101)).collect();
+        let events = agent.accept(&Message::List(list), time);
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3], Event::HighChurn { changes: 3 });
+
+        // the fail cutoff is doubled while churn is high
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        assert!(agent.detect(time).is_empty());
+        time += FAIL_CUTOFF;
+        let events = agent.detect(time);
+        assert_eq!(events.len(), 6);
+        assert!(events.iter().all(|e| matches!(e, Event::Remove(_))));
+
+        time += CHURN_WINDOW;
+        assert_eq!(agent.detect(time), vec![Event::ChurnSubsided]);
+        assert!(agent.detect(time).is_empty());
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_warm_up(2 * FAIL_CUTOFF);
+        agent.add_known_peers(vec![addr(2), addr(3)], time);
+
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        agent.accept(&Message::Ping(info(3, 101)), time);
+        assert!(agent.detect(time).is_empty());
+        assert_eq!(agent.members().len(), 3);
+
+        time += FAIL_CUTOFF;
+        assert_eq!(
+            agent.detect(time),
+            vec![Event::Remove(Record {
+                info: info(2, 0),
+                time: time - PING_CUTOFF - 2 * FAIL_CUTOFF,
+                down: time,
+            })]
+        );
+    }
+
+    #[test]
+    fn test_members_by() {
+        let time = 1000000000;
+        let mut agent = agent(3, time, 101);
+        agent.add_known_peers(vec![addr(1), addr(2), addr(4)], time);
+
+        let ssd = |record: &Record| [1, 3].contains(&record.info.addr.port);
+        let order: Vec<u16> = agent
+            .members_by(|record| !ssd(record))
+            .iter()
+            .map(|record| record.info.addr.port)
+            .collect();
+        assert_eq!(order, vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn test_member_priority() {
+        let time = 1000000000;
+        let ssd = |record: &Record| [1, 3].contains(&record.info.addr.port);
+        let mut agent = agent(3, time, 101).with_member_priority(move |record| !ssd(record) as i64);
+        agent.add_known_peers(vec![addr(1), addr(2), addr(4), addr(5)], time);
+
+        let ports = |records: Vec<Record>| -> Vec<u16> {
+            records.iter().map(|record| record.info.addr.port).collect()
+        };
+        assert_eq!(ports(agent.members_by_priority()), vec![1, 3, 2, 4, 5]);
+
+        for i in 0..32 {
+            let key = format!("key-{}", i);
+            assert!([1, 3].contains(&agent.pick_owner(&key).unwrap().addr().port));
+            let replicas: Vec<u16> = agent
+                .pick_replicas(&key, 3)
+                .iter()
+                .map(|member| member.addr().port)
+                .collect();
+            assert!([1, 3].contains(&replicas[1]));
+            assert!(![1, 3].contains(&replicas[2]));
+        }
+
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..32 {
+            assert_eq!(ports(agent.sample_with(1, &mut rng)), vec![1]);
+            let sample = ports(agent.sample_with(2, &mut rng));
+            assert_eq!(sample[0], 1);
+            assert!([2, 4, 5].contains(&sample[1]));
+            let weighted = ports(agent.sample_weighted_with(1, |_| 1.0, &mut rng));
+            assert_eq!(weighted, vec![1]);
+        }
+    }
+
+    #[test]
+    fn test_decision_trace() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101)
+            .with_topology(StaticNeighbors::new(vec![addr(2)]))
+            .with_hidden_members(vec![addr(3)])
+            .with_decision_trace();
+        agent.add_known_peers(vec![addr(2), addr(3), addr(4), addr(5), addr(6)], time);
+
+        time += PING_CUTOFF;
+        agent.accept(
+            &Message::List(smallvec![info(2, 101), info(3, 101), info(4, 101), info(5, 101)]),
+            time,
+        );
+        time += FAIL_CUTOFF;
+        agent.accept(
+            &Message::List(smallvec![info(2, 102), info(3, 102), info(4, 102)]),
+            time,
+        );
+        agent.detect(time);
+        assert!(agent.last_trace().unwrap().targets.is_empty());
+
+        agent.gossip(time);
+        assert_eq!(
+            agent.last_trace(),
+            Some(&Trace {
+                time,
+                targets: vec![addr(2)],
+                unselected: vec![addr(3), addr(4)],
+                down: vec![addr(6)],
+                quiet: vec![addr(5)],
+                included: vec![addr(2), addr(4), addr(1)],
+            })
+        );
+    }
+
+    #[test]
+    fn test_record_accessors() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        agent.accept(&Message::Ping(info(2, 101)), time);
+        time += PING_CUTOFF + FAIL_CUTOFF;
+
+        let members = agent.members_sorted();
+        assert!(agent.is_self(&members[0]));
+        assert!(!agent.is_self(&members[1]));
+        assert_eq!(members[1].addr(), addr(2));
+        assert_eq!(members[1].state(), State::Alive);
+        assert_eq!(members[1].age(time), PING_CUTOFF + FAIL_CUTOFF);
+
+        let member = agent.member(&addr(2)).unwrap();
+        assert!(!member.is_self());
+        assert_eq!(member.record(), members[1]);
+        assert_eq!(member.beat(), 101);
+        assert!(agent.member(&addr(1)).unwrap().is_self());
+        assert_eq!(agent.member(&addr(3)), None);
+
+        match agent.detect(time).as_slice() {
+            [Event::Remove(record)] => assert_eq!(record.state(), State::Down),
+            events => panic!("unexpected events: {:?}", events),
+        }
+        assert_eq!(agent.member(&addr(2)).unwrap().state(), State::Down);
+    }
+
+    #[test]
+    fn test_params() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        let same = agent.params(time);
+        let other = Message::Params {
+            ping_cutoff: PING_CUTOFF,
+            fail_cutoff: 2 * FAIL_CUTOFF,
+            time,
+        };
+
+        assert!(agent.accept_from(addr(2), &same, time).is_empty());
+        assert!(agent.accept(&other, time).is_empty());
+        assert_eq!(
+            agent.accept_from(addr(2), &other, time),
+            vec![Event::Incompatible {
+                addr: addr(2),
+                ping_cutoff: PING_CUTOFF,
+                fail_cutoff: 2 * FAIL_CUTOFF,
+            }]
+        );
+        assert!(agent.accept_from(addr(2), &other, time).is_empty());
+        assert!(agent.accept_from(addr(2), &same, time).is_empty());
+        assert_eq!(agent.accept_from(addr(2), &other, time).len(), 1);
+        assert_eq!(agent.stats().events.get("incompatible"), Some(&2));
+        assert_eq!(agent.members().len(), 1);
+    }
+
+    #[test]
+    fn test_skew_detection() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101).with_skew_detection(1000);
+        let ahead = |agent: &Agent, time, skew| agent.params((time as i64 + skew) as u64);
+
+        assert_eq!(agent.skew(&addr(2)), None);
+        let params = ahead(&agent, time, 400);
+        assert!(agent.accept_from(addr(2), &params, time).is_empty());
+        assert_eq!(agent.skew(&addr(2)), Some(400));
+        let params = ahead(&agent, time, -4000);
+        assert_eq!(
+            agent.accept_from(addr(3), &params, time),
+            vec![Event::ClockSkew {
+                addr: addr(3),
+                skew: -4000
+            }]
+        );
+
+        // reported once while high, again after it recovered
+        let mut events = vec![];
+        for _ in 0..20 {
+            time += PING_CUTOFF;
+            let params = ahead(&agent, time, -4000);
+            events.extend(agent.accept_from(addr(3), &params, time));
+        }
+        assert!(events.is_empty());
+        for _ in 0..30 {
+            time += PING_CUTOFF;
+            let params = ahead(&agent, time, 0);
+            events.extend(agent.accept_from(addr(3), &params, time));
+        }
+        assert!(events.is_empty());
+        assert!(agent.skew(&addr(3)).unwrap().abs() < 10);
+        let params = ahead(&agent, time, 8000);
+        assert_eq!(agent.accept_from(addr(3), &params, time).len(), 1);
+        assert!(agent.skew(&addr(3)).unwrap() > 1000);
+    }
+
+    #[test]
+    fn test_pick_owner() {
+        let mut time = 1000000000;
+
+        let alone = agent(1, time, 101);
+        let owner = alone.pick_owner("key").unwrap();
+        assert!(owner.is_self());
+        assert_eq!(owner.addr(), addr(1));
+
+        let mut one = agent(1, time, 101);
+        let mut two = agent(2, time, 101);
+        one.accept(&Message::List(smallvec![info(2, 101), info(3, 101)]), time);
+        two.accept(&Message::List(smallvec![info(1, 101), info(3, 101)]), time);
+
+        let keys: Vec<String> = (0..32).map(|i| format!("key-{}", i)).collect();
+        for key in &keys {
+            assert_eq!(
+                one.pick_owner(key).unwrap().addr(),
+                two.pick_owner(key).unwrap().addr()
+            );
+            let replicas = one.pick_replicas(key, 2);
+            assert_eq!(replicas.len(), 2);
+            assert_eq!(Some(replicas[0]), one.pick_owner(key));
+            assert_ne!(replicas[0].addr(), replicas[1].addr());
+        }
+        assert_eq!(one.pick_replicas("key", 5).len(), 3);
+
+        let owners: Vec<Addr> = keys
+            .iter()
+            .map(|key| one.pick_owner(key).unwrap().addr())
+            .collect();
+        time += PING_CUTOFF + FAIL_CUTOFF;
+        one.accept(&Message::Ping(info(2, 102)), time);
+        one.detect(time);
+        for (key, owner) in keys.iter().zip(owners) {
+            if owner != addr(3) {
+                assert_eq!(one.pick_owner(key).unwrap().addr(), owner);
+            } else {
+                assert_ne!(one.pick_owner(key).unwrap().addr(), addr(3));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ignore_unroutable() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        let no_port = Info {
+            addr: Addr {
+                host: 0x7f000001,
+                port: 0,
+            },
+            beat: 101,
+        };
+        let no_host = Info {
+            addr: Addr {
+                host: 0,
+                port: 12000,
+            },
+            beat: 101,
+        };
+        assert!(agent.accept_from(addr(2), &Message::Ping(no_port), time).is_empty());
+        assert!(agent
+            .accept(&Message::List(smallvec![no_port, no_host]), time)
+            .is_empty());
+        assert_eq!(agent.members().len(), 1);
+    }
+
+    #[test]
+    fn test_set_cutoffs() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        agent.accept(&Message::Ping(info(2, 101)), time);
+        agent.set_cutoffs(4 * PING_CUTOFF, 4 * FAIL_CUTOFF);
+        assert_eq!(
+            agent.params(time),
+            Message::Params {
+                ping_cutoff: 4 * PING_CUTOFF,
+                fail_cutoff: 4 * FAIL_CUTOFF,
+                time,
+            }
+        );
+
+        time += 2 * PING_CUTOFF;
+        assert_eq!(agent.gossip(time).len(), 1);
+        time += 2 * FAIL_CUTOFF;
+        assert!(agent.detect(time).is_empty());
+
+        agent.set_cutoffs(PING_CUTOFF, FAIL_CUTOFF);
+        assert_eq!(agent.detect(time).len(), 1);
+    }
+
+    #[test]
+    fn test_rendezvous_pinned() {
+        let local = |port| Addr {
+            host: 0x7f000001,
+            port,
+        };
+        let zero = Addr { host: 0, port: 0 };
+        assert_eq!(rendezvous(b"", &zero), 0x593fd83ad9b7851a);
+        assert_eq!(rendezvous(b"key-1", &local(12000)), 0xec706ab773febfb0);
+        assert_eq!(rendezvous(b"key-1", &local(12001)), 0x73d1089321b23737);
+    }
+
+    #[test]
+    fn test_take_params() {
+        let mut time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        assert!(agent.take_params(time).is_empty());
+
+        agent.accept(&Message::List(smallvec![info(2, 101), info(3, 101)]), time);
+        let targets: Vec<Addr> = agent
+            .take_params(time)
+            .into_iter()
+            .map(|(addr, message)| {
+                assert_eq!(message, agent.params(time));
+                addr
+            })
+            .collect();
+        assert_eq!(targets, vec![addr(2), addr(3)]);
+        assert!(agent.take_params(time).is_empty());
+
+        let start = time;
+        let mut beat = 101;
+        while time + PING_CUTOFF < start + PARAMS_INTERVAL {
+            time += PING_CUTOFF;
+            beat += 1;
+            agent.accept(&Message::List(smallvec![info(2, beat), info(3, beat)]), time);
+            assert!(agent.take_params(time).is_empty());
+        }
+        time = start + PARAMS_INTERVAL;
+        agent.accept(&Message::List(smallvec![info(2, beat + 1), info(3, beat + 1)]), time);
+        assert_eq!(agent.take_params(time).len(), 2);
+        assert_eq!(agent.stats().sent.get("params").map(|c| c.count), Some(4));
+    }
+
+    #[test]
+    fn test_incompatible_bounded() {
+        let time = 1000000000;
+
+        let mut agent = agent(1, time, 101);
+        let other = Message::Params {
+            ping_cutoff: PING_CUTOFF,
+            fail_cutoff: 2 * FAIL_CUTOFF,
+            time,
+        };
+        for port in 0..2 * MAX_INCOMPATIBLE as u16 {
+            let from = Addr { host: 1, port };
+            assert_eq!(agent.accept_from(from, &other, time).len(), 1);
+        }
+        assert_eq!(agent.incompatible.len(), MAX_INCOMPATIBLE);
+    }
 }
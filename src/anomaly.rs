@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use gossip_peer::agent::Addr;
+
+/// Most sources logged per kind of anomaly in one interval, the busiest first; the rest are
+/// summed up in one line.
+const MAX_LOGGED: usize = 10;
+
+/// Most sources counted separately per kind in one interval; packets from further sources are
+/// only counted in the summary line.
+const MAX_TRACKED: usize = 1024;
+
+pub struct Anomalies {
+    interval: u64,
+    since: u64,
+    recent: HashMap<(&'static str, Addr), u64>,
+    sources: HashMap<&'static str, usize>,
+    untracked: HashMap<&'static str, u64>,
+    totals: HashMap<&'static str, u64>,
+}
+
+impl Anomalies {
+    pub fn new(interval: u64, now: u64) -> Self {
+        Self {
+            interval,
+            since: now,
+            recent: HashMap::new(),
+            sources: HashMap::new(),
+            untracked: HashMap::new(),
+            totals: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, kind: &'static str, addr: Addr) {
+        *self.totals.entry(kind).or_insert(0) += 1;
+        if let Some(count) = self.recent.get_mut(&(kind, addr)) {
+            *count += 1;
+            return;
+        }
+        let sources = self.sources.entry(kind).or_insert(0);
+        if *sources < MAX_TRACKED {
+            *sources += 1;
+            self.recent.insert((kind, addr), 1);
+        } else {
+            *self.untracked.entry(kind).or_insert(0) += 1;
+        }
+    }
+
+    pub fn flush(&mut self, now: u64) {
+        if now - self.since < self.interval {
+            return;
+        }
+        let secs = (now - self.since) / 1000;
+        let mut recent: Vec<_> = self.recent.drain().collect();
+        recent.sort_by_key(|((kind, addr), count)| {
+            (*kind, u64::MAX - count, addr.host(), addr.port())
+        });
+        let mut kinds: Vec<&'static str> = self.sources.drain().map(|(kind, _)| kind).collect();
+        kinds.sort();
+        for kind in kinds {
+            let mut sources = recent.iter().filter(|((k, _), _)| *k == kind);
+            for ((_, addr), count) in sources.by_ref().take(MAX_LOGGED) {
+                warn!(
+                    "dropped {} {} packets from {:?} in last {}s",
+                    count, kind, addr, secs
+                );
+            }
+            let (more, mut packets) =
+                sources.fold((0, 0), |(n, sum), (_, count)| (n + 1, sum + count));
+            let untracked = self.untracked.remove(kind).unwrap_or(0);
+            packets += untracked;
+            if packets > 0 {
+                warn!(
+                    "{}{} more sources suppressed: dropped {} {} packets in last {}s",
+                    more,
+                    if untracked > 0 { "+" } else { "" },
+                    packets,
+                    kind,
+                    secs
+                );
+            }
+        }
+        self.since = now;
+    }
+
+    pub fn totals(&self) -> Vec<(&'static str, u64)> {
+        let mut totals: Vec<_> = self.totals.iter().map(|(k, v)| (*k, *v)).collect();
+        totals.sort();
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anomalies() {
+        let addr = Addr::new(1, 2);
+        let mut anomalies = Anomalies::new(1000, 0);
+        anomalies.record("malformed", addr);
+        anomalies.record("malformed", addr);
+        anomalies.record("denied", addr);
+
+        anomalies.flush(999);
+        assert_eq!(anomalies.recent.len(), 2);
+        anomalies.flush(1000);
+        assert!(anomalies.recent.is_empty());
+
+        assert_eq!(anomalies.totals(), vec![("denied", 1), ("malformed", 2)]);
+    }
+
+    #[test]
+    fn test_sources_capped() {
+        let mut anomalies = Anomalies::new(1000, 0);
+        for port in 0..2 * MAX_TRACKED as u16 {
+            anomalies.record("malformed", Addr::new(1, port));
+        }
+        anomalies.record("malformed", Addr::new(1, 0));
+        assert_eq!(anomalies.recent.len(), MAX_TRACKED);
+        assert_eq!(anomalies.untracked["malformed"], MAX_TRACKED as u64);
+        assert_eq!(anomalies.recent[&("malformed", Addr::new(1, 0))], 2);
+
+        anomalies.flush(1000);
+        assert!(anomalies.recent.is_empty());
+        assert!(anomalies.sources.is_empty());
+        assert!(anomalies.untracked.is_empty());
+        assert_eq!(
+            anomalies.totals(),
+            vec![("malformed", 2 * MAX_TRACKED as u64 + 1)]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use log::warn;
+
+use gossip_peer::agent::{self, Addr};
+
+use crate::rotate::{RotatingFile, KEEP, MAX_SIZE};
+
+/// Most senders whose denied messages are counted separately in one interval; messages from
+/// further senders are counted together.
+const MAX_DENIED: usize = 1024;
+
+pub struct Audit {
+    file: RotatingFile,
+    interval: u64,
+    since: u64,
+    denied: HashMap<Addr, u64>,
+    others: u64,
+}
+
+impl Audit {
+    /// Denied messages are written as one line per sender every `interval` millis.
+    pub fn open<P: Into<PathBuf>>(path: P, interval: u64, now: u64) -> io::Result<Self> {
+        let file = RotatingFile::open(path, MAX_SIZE, KEEP)?;
+        Ok(Self {
+            file,
+            interval,
+            since: now,
+            denied: HashMap::new(),
+            others: 0,
+        })
+    }
+
+    pub fn record(&mut self, event: &str, addr: &Addr) {
+        let line = format!(
+            "{{\"time\":{},\"event\":\"{}\",\"addr\":\"{:?}\"}}\n",
+            agent::get_current_millis(),
+            event,
+            addr
+        );
+        self.write(&line);
+    }
+
+    /// Count a message dropped from a disallowed sender, written out by `flush`.
+    pub fn deny(&mut self, addr: &Addr) {
+        if let Some(count) = self.denied.get_mut(addr) {
+            *count += 1;
+        } else if self.denied.len() < MAX_DENIED {
+            self.denied.insert(*addr, 1);
+        } else {
+            self.others += 1;
+        }
+    }
+
+    pub fn flush(&mut self, now: u64) {
+        if now - self.since < self.interval {
+            return;
+        }
+        self.write_denied(now);
+    }
+
+    fn write_denied(&mut self, now: u64) {
+        let mut denied: Vec<_> = self.denied.drain().collect();
+        denied.sort_by_key(|(addr, _)| (addr.host(), addr.port()));
+        let mut lines: Vec<String> = denied
+            .into_iter()
+            .map(|(addr, count)| format!("\"{:?}\",\"count\":{}", addr, count))
+            .collect();
+        if self.others > 0 {
+            lines.push(format!("\"others\",\"count\":{}", self.others));
+            self.others = 0;
+        }
+        for line in lines {
+            let line = format!(
+                "{{\"time\":{},\"event\":\"deny\",\"since\":{},\"addr\":{}}}\n",
+                now, self.since, line
+            );
+            self.write(&line);
+        }
+        self.since = now;
+    }
+
+    fn write(&mut self, line: &str) {
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("audit log write failed: {}", e);
+        }
+    }
+}
+
+impl Drop for Audit {
+    fn drop(&mut self) {
+        self.write_denied(agent::get_current_millis());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deny_aggregated() {
+        let path = std::env::temp_dir().join(format!("gossip-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut audit = Audit::open(&path, 1000, 0).unwrap();
+        let addr = Addr::new(1, 2);
+        for _ in 0..100 {
+            audit.deny(&addr);
+        }
+        for port in 0..MAX_DENIED as u16 {
+            audit.deny(&Addr::new(2, port));
+        }
+        audit.flush(999);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        audit.flush(1000);
+        drop(audit);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), MAX_DENIED + 1);
+        assert_eq!(
+            lines[0],
+            "{\"time\":1000,\"event\":\"deny\",\"since\":0,\"addr\":\"0.0.0.1:2\",\"count\":100}"
+        );
+        assert!(lines[MAX_DENIED].ends_with("\"addr\":\"others\",\"count\":1}"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
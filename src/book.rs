@@ -0,0 +1,230 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::agent::Addr;
+
+/// How long a resolved address is trusted before it is looked up again.
+pub const TTL: Duration = Duration::from_secs(300);
+
+/// Seeds by the names they were given (`node-3.internal:7946`) and the addresses those last
+/// resolved to, so logs can show the name and a moved seed can be found again.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: Vec<Entry>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    addr: Option<Addr>,
+    resolved_at: Option<Instant>,
+    pending: bool,
+}
+
+impl AddressBook {
+    pub fn new(names: Vec<String>) -> Self {
+        let entries = names
+            .into_iter()
+            .map(|name| Entry {
+                name,
+                addr: None,
+                resolved_at: None,
+                pending: false,
+            })
+            .collect();
+        Self { entries, ttl: TTL }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Resolve entries that `pending` returns, blocking until all lookups are done. An entry
+    /// that fails to resolve keeps its previous address; the errors are returned by name.
+    pub fn resolve(&mut self, failed: &[Addr]) -> Vec<(String, io::Error)> {
+        let now = Instant::now();
+        let results = self
+            .pending(failed, now)
+            .into_iter()
+            .map(|name| {
+                let result = resolve(&name);
+                (name, result)
+            })
+            .collect();
+        self.update(results, now)
+    }
+
+    /// Names to look up: entries that never resolved, whose address is in `failed` or older
+    /// than the TTL. They are marked pending until `update` receives their results.
+    pub fn pending(&mut self, failed: &[Addr], now: Instant) -> Vec<String> {
+        let ttl = self.ttl;
+        self.entries
+            .iter_mut()
+            .filter(|entry| !entry.pending)
+            .filter(|entry| {
+                let fresh = entry
+                    .resolved_at
+                    .is_some_and(|at| now.saturating_duration_since(at) < ttl);
+                !fresh || entry.addr.is_none_or(|addr| failed.contains(&addr))
+            })
+            .map(|entry| {
+                entry.pending = true;
+                entry.name.clone()
+            })
+            .collect()
+    }
+
+    /// Apply lookup results; failed lookups keep the previous address and are returned.
+    pub fn update(
+        &mut self,
+        results: Vec<(String, io::Result<Addr>)>,
+        now: Instant,
+    ) -> Vec<(String, io::Error)> {
+        let mut errors = Vec::new();
+        for (name, result) in results {
+            let entry = match self.entries.iter_mut().find(|entry| entry.name == name) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            entry.pending = false;
+            match result {
+                Ok(addr) => {
+                    entry.addr = Some(addr);
+                    entry.resolved_at = Some(now);
+                }
+                Err(e) => errors.push((name, e)),
+            }
+        }
+        errors
+    }
+
+    pub fn addrs(&self) -> Vec<Addr> {
+        self.entries.iter().filter_map(|entry| entry.addr).collect()
+    }
+
+    /// Resolved entries as (address, name) pairs, for `Agent::set_names`.
+    pub fn names(&self) -> Vec<(Addr, String)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| Some((entry.addr?, entry.name.clone())))
+            .collect()
+    }
+
+    pub fn name(&self, addr: &Addr) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.addr.as_ref() == Some(addr))
+            .map(|entry| entry.name.as_str())
+    }
+
+    /// The name an address was configured as, followed by the address itself.
+    pub fn display(&self, addr: &Addr) -> String {
+        match self.name(addr) {
+            Some(name) => format!("{} ({:?})", name, addr),
+            None => format!("{:?}", addr),
+        }
+    }
+}
+
+/// Resolve `host:port` to the first IPv4 address it has.
+pub fn resolve(name: &str) -> io::Result<Addr> {
+    name.to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .map(Addr::from)
+        .ok_or_else(|| {
+            let message = format!("{}: no IPv4 address", name);
+            io::Error::new(io::ErrorKind::NotFound, message)
+        })
+}
+
+/// Looks names up on a background thread, so a slow resolver never stalls the caller.
+pub struct Resolver {
+    requests: Sender<String>,
+    results: Receiver<(String, io::Result<Addr>)>,
+}
+
+impl Resolver {
+    pub fn spawn() -> Self {
+        let (requests, names) = mpsc::channel::<String>();
+        let (done, results) = mpsc::channel();
+        thread::spawn(move || {
+            for name in names {
+                let result = resolve(&name);
+                if done.send((name, result)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { requests, results }
+    }
+
+    pub fn request(&self, names: Vec<String>) {
+        for name in names {
+            let _ = self.requests.send(name);
+        }
+    }
+
+    /// Results finished since the last call, without waiting for the rest.
+    pub fn results(&self) -> Vec<(String, io::Result<Addr>)> {
+        self.results.try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_book() {
+        let names = vec![
+            "127.0.0.1:12000".to_string(),
+            "localhost:12001".to_string(),
+            "[::1]:12002".to_string(),
+        ];
+        let mut book = AddressBook::new(names);
+        let errors = book.resolve(&[]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "[::1]:12002");
+
+        let local = |port| Addr::new(u32::from_be_bytes([127, 0, 0, 1]), port);
+        assert_eq!(book.addrs(), vec![local(12000), local(12001)]);
+        assert_eq!(book.name(&local(12001)), Some("localhost:12001"));
+        assert_eq!(book.name(&local(12002)), None);
+        assert_eq!(book.names()[1], (local(12001), "localhost:12001".to_string()));
+        assert_eq!(book.display(&local(12001)), "localhost:12001 (127.0.0.1:12001)");
+
+        assert_eq!(book.resolve(&[local(12001)]).len(), 1);
+        assert_eq!(book.addrs(), vec![local(12000), local(12001)]);
+    }
+
+    #[test]
+    fn test_pending() {
+        let local = |port| Addr::new(u32::from_be_bytes([127, 0, 0, 1]), port);
+        let mut book = AddressBook::new(vec!["127.0.0.1:12000".to_string()])
+            .with_ttl(Duration::from_secs(60));
+        let now = Instant::now();
+        let names = book.pending(&[], now);
+        assert_eq!(names, vec!["127.0.0.1:12000"]);
+        assert!(book.pending(&[], now).is_empty());
+
+        let resolver = Resolver::spawn();
+        resolver.request(names);
+        let mut results = Vec::new();
+        while results.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+            results = resolver.results();
+        }
+        assert!(book.update(results, now).is_empty());
+        assert_eq!(book.addrs(), vec![local(12000)]);
+
+        assert!(book.pending(&[], now + Duration::from_secs(30)).is_empty());
+        assert_eq!(book.pending(&[local(12000)], now).len(), 1);
+        book.update(vec![("127.0.0.1:12000".to_string(), Ok(local(12000)))], now);
+        assert_eq!(book.pending(&[], now + Duration::from_secs(60)).len(), 1);
+    }
+}
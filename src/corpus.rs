@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use gossip_peer::agent::Message;
+use gossip_peer::wire;
+
+/// Keeps one sample of every distinct inbound message shape (code, length, whether it parses)
+/// in a directory, as seed inputs for fuzzing and conformance tests. Samples already in the
+/// directory count as seen, so the corpus grows across runs.
+pub struct Corpus {
+    dir: PathBuf,
+    seen: HashSet<String>,
+}
+
+impl Corpus {
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let seen = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect();
+        Ok(Self { dir, seen })
+    }
+
+    /// Record a received datagram, with the message it was parsed into if it parses.
+    pub fn record(&mut self, bytes: &[u8], message: Option<&Message>) {
+        let name = shape(bytes, message);
+        if self.seen.contains(&name) {
+            return;
+        }
+        match fs::write(self.dir.join(&name), sanitize(bytes)) {
+            Ok(()) => info!("corpus: new message shape {}", name),
+            Err(e) => warn!("corpus: failed to write {}: {}", name, e),
+        }
+        self.seen.insert(name);
+    }
+}
+
+fn shape(bytes: &[u8], message: Option<&Message>) -> String {
+    let code = bytes.first().map(|code| format!("{:02x}", code));
+    let valid = if message.is_some() {
+        "valid"
+    } else {
+        "invalid"
+    };
+    format!(
+        "{}-{}-{}.bin",
+        code.as_deref().unwrap_or("empty"),
+        bytes.len(),
+        valid
+    )
+}
+
+/// Zero every host address field the layout puts in the datagram, truncated ones included.
+fn sanitize(bytes: &[u8]) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    let infos = match bytes.first() {
+        Some(&wire::PING) => wire::CODE_LEN,
+        Some(&wire::LIST) => wire::CODE_LEN + wire::COUNT_LEN,
+        _ => return bytes,
+    };
+    let len = bytes.len();
+    for at in (infos..len).step_by(wire::INFO_LEN) {
+        let end = (at + 4).min(len);
+        bytes[at..end].iter_mut().for_each(|b| *b = 0);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus() {
+        let dir = std::env::temp_dir().join(format!("gossip-peer-corpus-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut ping = vec![0, 10, 0, 0, 1, 0x2e, 0xe0];
+        ping.extend_from_slice(&7_u64.to_be_bytes());
+        let mut list = vec![1, 0, 0, 0, 2];
+        list.extend_from_slice(&ping[1..]);
+        list.extend_from_slice(&ping[1..3]);
+
+        let mut corpus = Corpus::open(&dir).unwrap();
+        for bytes in [&ping[..], &ping, &list, &[]] {
+            corpus.record(bytes, Message::parse(bytes).as_ref());
+        }
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "00-15-valid.bin",
+                "01-21-invalid.bin",
+                "empty-0-invalid.bin"
+            ]
+        );
+
+        let sample = fs::read(dir.join("00-15-valid.bin")).unwrap();
+        assert_eq!(sample[..7], [0, 0, 0, 0, 0, 0x2e, 0xe0]);
+        let sample = fs::read(dir.join("01-21-invalid.bin")).unwrap();
+        assert_eq!(sample[19..], [0, 0]);
+
+        assert_eq!(Corpus::open(&dir).unwrap().seen.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
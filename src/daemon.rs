@@ -0,0 +1,164 @@
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// A pidfile held for the life of the process. On unix it is locked with `flock`, so a second
+/// instance fails to take it whatever the file says, and a pidfile left by a crash is reused.
+pub struct Pidfile {
+    path: PathBuf,
+    file: File,
+}
+
+impl Pidfile {
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = lock(&path)?;
+        let mut pidfile = Self { path, file };
+        pidfile.update()?;
+        Ok(pidfile)
+    }
+
+    /// Record the current process id, e.g. again after `daemonize` forked.
+    pub fn update(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn lock(path: &PathBuf) -> io::Result<File> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(e);
+            }
+            let pid = fs::read_to_string(path).unwrap_or_default();
+            return Err(already_running(pid.trim()));
+        }
+        // The previous holder may have removed the file between our open and flock; then the
+        // lock is on an unlinked file and the path must be opened again.
+        let locked = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(file)
+            }
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(path: &PathBuf) -> io::Result<File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                let pid = fs::read_to_string(path).unwrap_or_default();
+                already_running(pid.trim())
+            }
+            _ => e,
+        })
+}
+
+fn already_running(pid: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("already running with pid {}", pid),
+    )
+}
+
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    fn fork() -> io::Result<()> {
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            _ => unsafe { libc::_exit(0) },
+        }
+    }
+
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fork()?;
+
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// There is no Windows service wrapper: the binary does not register with the service control
+/// manager, so it cannot be installed with `sc.exe create` directly. Run it under a wrapper such
+/// as NSSM, which stops it with Ctrl-C or console close, both handled as a graceful shutdown.
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "daemonize is only supported on unix, run under a service wrapper instead",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pidfile() {
+        let path = std::env::temp_dir().join(format!("gossip-peer-{}.pid", std::process::id()));
+
+        let mut pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        let error = Pidfile::create(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        pidfile.update().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // left behind by a crash: nothing holds the lock
+        fs::write(&path, "99999999\n").unwrap();
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt::Display;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gossip_peer::hooks::Overflow;
+use gossip_peer::{agent, book};
+
+use crate::probe;
+use crate::quiet::QuietHours;
+use crate::{take_flag, take_option};
+
+// 2020-01-01T00:00:00Z
+const CLOCK_FLOOR_MILLIS: u64 = 1577836800000;
+const CLOCK_DRIFT_MILLIS: u64 = 50;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Check the options, port and seeds the node would be started with.
+pub fn run(args: &[String]) -> bool {
+    let mut args = args.to_vec();
+    let mut ok = check_options(&mut args);
+    let port = match args.first().map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => port,
+        Some(Err(e)) => return report("port", Err(format!("{}: {}", args[0], e))),
+        None => return report("port", Err("missing".to_string())),
+    };
+    ok &= report("port", check_bind(port));
+    for seed in &args[1..] {
+        let addr = match check_seed(seed) {
+            Ok(addr) => addr,
+            Err(e) => {
+                ok &= report("seed", Err(e));
+                continue;
+            }
+        };
+        ok &= report("seed", check_reachable(addr));
+        #[cfg(target_os = "linux")]
+        {
+            ok &= report("datagram", check_datagram(addr));
+        }
+    }
+    ok &= report("clock", check_clock());
+    ok
+}
+
+pub fn report(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(message) => {
+            println!("ok   {}: {}", name, message);
+            true
+        }
+        Err(message) => {
+            println!("FAIL {}: {}", name, message);
+            false
+        }
+    }
+}
+
+/// Take the node's options out of `args`, reporting any that the node would refuse.
+fn check_options(args: &mut Vec<String>) -> bool {
+    for flag in ["--static", "--trace-rounds", "--daemonize"] {
+        take_flag(args, flag);
+    }
+    let mut ok = true;
+    for (name, min) in [
+        ("--warm-up", 0),
+        ("--fanout", 1),
+        ("--recv-budget", 1),
+        ("--hook-workers", 0),
+        ("--hook-timeout", 1),
+        ("--hook-queue", 0),
+    ] {
+        if let Some(value) = take_option(args, name) {
+            ok &= report("option", check_number(name, &value, min));
+        }
+    }
+    if let Some(value) = take_option(args, "--quiet-hours") {
+        ok &= report("option", check_parse::<QuietHours, _>("--quiet-hours", &value));
+    }
+    if let Some(value) = take_option(args, "--hook-overflow") {
+        ok &= report("option", check_parse::<Overflow, _>("--hook-overflow", &value));
+    }
+    if let Some(value) = take_option(args, "--host") {
+        ok &= report("option", check_parse::<Ipv4Addr, _>("--host", &value));
+    }
+    while let Some(value) = take_option(args, "--hidden") {
+        ok &= report("option", check_seed(&value).map(|addr| format!("--hidden {}", addr)));
+    }
+    for name in ["--pidfile", "--audit-log"] {
+        if let Some(value) = take_option(args, name) {
+            ok &= report("option", check_parent(name, &value));
+        }
+    }
+    for name in ["--corpus-dir", "--event-hook"] {
+        take_option(args, name);
+    }
+    ok
+}
+
+fn check_parse<T, E>(name: &str, value: &str) -> Result<String, String>
+where
+    T: std::str::FromStr<Err = E>,
+    E: Display,
+{
+    value
+        .parse::<T>()
+        .map(|_| format!("{} {}", name, value))
+        .map_err(|e| format!("{} {}: {}", name, value, e))
+}
+
+fn check_number(name: &str, value: &str, min: u64) -> Result<String, String> {
+    match value.parse::<u64>() {
+        Ok(n) if n >= min => Ok(format!("{} {}", name, value)),
+        Ok(_) => Err(format!("{} {}: must be at least {}", name, value, min)),
+        Err(e) => Err(format!("{} {}: {}", name, value, e)),
+    }
+}
+
+fn check_parent(name: &str, path: &str) -> Result<String, String> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(format!(
+            "{} {}: directory {} does not exist",
+            name,
+            path,
+            dir.display()
+        )),
+        _ => Ok(format!("{} {}", name, path)),
+    }
+}
+
+fn check_bind(port: u16) -> Result<String, String> {
+    UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+        .map(|_| format!("UDP port {} can be bound", port))
+        .map_err(|e| format!("UDP port {} cannot be bound: {}", port, e))
+}
+
+fn check_seed(seed: &str) -> Result<SocketAddr, String> {
+    book::resolve(seed)
+        .map(|addr| addr.addr())
+        .map_err(|e| format!("{}: {}", seed, e))
+}
+
+fn check_reachable(addr: SocketAddr) -> Result<String, String> {
+    probe::probe(addr, PROBE_TIMEOUT)
+        .map(|rtt| format!("{} replied in {:.3} ms", addr, rtt.as_secs_f64() * 1000.0))
+        .map_err(|e| format!("{} did not reply to probe: {}", addr, e))
+}
+
+fn check_clock() -> Result<String, String> {
+    let start = Instant::now();
+    let wall = agent::get_current_millis();
+    if wall < CLOCK_FLOOR_MILLIS {
+        return Err(format!("system time {} ms since epoch is in the past", wall));
+    }
+    thread::sleep(Duration::from_millis(100));
+    let elapsed = start.elapsed().as_millis() as u64;
+    let wall_elapsed = agent::get_current_millis().saturating_sub(wall);
+    let drift = (elapsed as i64 - wall_elapsed as i64).unsigned_abs();
+    if drift > CLOCK_DRIFT_MILLIS {
+        return Err(format!(
+            "system time moved {} ms during {} ms of monotonic time",
+            wall_elapsed, elapsed
+        ));
+    }
+    Ok("system time is sane and steady".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn check_datagram(addr: SocketAddr) -> Result<String, String> {
+    let len = probe::probe_mtu(addr, PROBE_TIMEOUT)
+        .map_err(|e| format!("path MTU to {} unknown: {}", addr, e))?;
+    if len < agent::MAX_DATAGRAM {
+        return Err(format!(
+            "datagrams over {} bytes do not reach {} unfragmented, gossip sends up to {}",
+            len,
+            addr,
+            agent::MAX_DATAGRAM
+        ));
+    }
+    Ok(format!(
+        "datagrams of up to {} bytes reach {} unfragmented",
+        agent::MAX_DATAGRAM,
+        addr
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_seed() {
+        assert!(check_seed("127.0.0.1:12000").is_ok());
+        assert!(check_seed("localhost:12000").is_ok());
+        assert!(check_seed("[::1]:12000").is_err());
+        assert!(check_seed("localhost").is_err());
+    }
+
+    #[test]
+    fn test_check_options() {
+        let mut args: Vec<String> = ["--fanout", "3", "--static", "12001", "127.0.0.1:12000"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert!(check_options(&mut args));
+        assert_eq!(args, vec!["12001", "127.0.0.1:12000"]);
+
+        for bad in [
+            ["--fanout", "0"],
+            ["--quiet-hours", "22"],
+            ["--hook-overflow", "wait"],
+            ["--host", "localhost"],
+            ["--pidfile", "/nonexistent/gossip.pid"],
+        ] {
+            let mut args: Vec<String> = bad.iter().map(|arg| arg.to_string()).collect();
+            assert!(!check_options(&mut args), "{:?}", bad);
+            assert!(args.is_empty());
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::agent::Event;
+
+/// Longest `Overflow::Block` waits for room before dropping the oldest event instead, so a
+/// slow hook never stalls the caller for long.
+pub const MAX_BLOCK: Duration = Duration::from_millis(50);
+
+/// Longest dropping `Hooks` waits for hooks still running. Commands still running then are
+/// killed; callbacks cannot be, so their workers are left to finish on their own.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An in-process hook, called with the event's sequence number and the event.
+pub type Callback = Box<dyn Fn(u64, &Event) + Send + Sync>;
+
+/// What `submit` does when the hook queue is full.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overflow {
+    /// Wait up to `MAX_BLOCK` for a worker to take a queued event, then fall back to
+    /// `DropOldest`: the caller is never blocked for longer, so events can still be dropped.
+    Block,
+    /// Drop the event being submitted.
+    DropNewest,
+    /// Drop the oldest queued event to make room.
+    DropOldest,
+    /// Replace a queued event about the same member (or of the same kind, for events not
+    /// about a member) with the new one; drop the oldest if there is none.
+    Coalesce,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "coalesce" => Ok(Overflow::Coalesce),
+            _ => Err(format!("unknown hook overflow policy: {}", s)),
+        }
+    }
+}
+
+enum Handler {
+    Command { command: String, timeout: Duration },
+    Callback(Callback),
+}
+
+struct Job {
+    seq: u64,
+    key: String,
+    event: Event,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    closed: Option<Instant>,
+}
+
+struct Queue {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: Overflow,
+    ready: Condvar,
+    space: Condvar,
+}
+
+impl Queue {
+    fn push(&self, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        if state.jobs.len() >= self.capacity {
+            match self.overflow {
+                Overflow::Block => {
+                    let (waited, _) = self
+                        .space
+                        .wait_timeout_while(state, MAX_BLOCK, |state| {
+                            state.jobs.len() >= self.capacity && state.closed.is_none()
+                        })
+                        .unwrap();
+                    state = waited;
+                    if state.jobs.len() >= self.capacity {
+                        drop_oldest(&mut state);
+                    }
+                }
+                Overflow::DropNewest => {
+                    warn!(
+                        "event hook queue is full, dropped #{}: {:?}",
+                        job.seq, job.event
+                    );
+                    return;
+                }
+                Overflow::DropOldest => drop_oldest(&mut state),
+                Overflow::Coalesce => {
+                    let at = state.jobs.iter().position(|queued| queued.key == job.key);
+                    match at.and_then(|at| state.jobs.remove(at)) {
+                        Some(old) => {
+                            debug!("event hook #{} coalesced into #{}", old.seq, job.seq);
+                        }
+                        None => drop_oldest(&mut state),
+                    }
+                }
+            }
+        }
+        state.jobs.push_back(job);
+        self.ready.notify_one();
+    }
+
+    /// Next job to run, or `None` once the queue is closed. Jobs still queued at that point are
+    /// skipped.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed.is_some() {
+                for job in state.jobs.drain(..) {
+                    debug!("event hook skipped on shutdown: {:?}", job.event);
+                }
+                return None;
+            }
+            if let Some(job) = state.jobs.pop_front() {
+                self.space.notify_one();
+                return Some(job);
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = Some(Instant::now());
+        self.ready.notify_all();
+        self.space.notify_all();
+    }
+}
+
+fn drop_oldest(state: &mut State) {
+    if let Some(old) = state.jobs.pop_front() {
+        warn!(
+            "event hook queue is full, dropped #{}: {:?}",
+            old.seq, old.event
+        );
+    }
+}
+
+pub struct Hooks {
+    seq: Cell<u64>,
+    queue: Arc<Queue>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Hooks {
+    pub fn new(
+        command: String,
+        workers: usize,
+        capacity: usize,
+        overflow: Overflow,
+        timeout: Duration,
+    ) -> Self {
+        Self::start(
+            Handler::Command { command, timeout },
+            workers,
+            capacity,
+            overflow,
+        )
+    }
+
+    /// Same as `new`, calling `callback` on the workers instead of running a command.
+    pub fn with_callback<F>(
+        callback: F,
+        workers: usize,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Self
+    where
+        F: Fn(u64, &Event) + Send + Sync + 'static,
+    {
+        Self::start(
+            Handler::Callback(Box::new(callback)),
+            workers,
+            capacity,
+            overflow,
+        )
+    }
+
+    fn start(handler: Handler, workers: usize, capacity: usize, overflow: Overflow) -> Self {
+        let handler = Arc::new(handler);
+        let queue = Arc::new(Queue {
+            state: Mutex::new(State::default()),
+            capacity: capacity.max(1),
+            overflow,
+            ready: Condvar::new(),
+            space: Condvar::new(),
+        });
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let handler = handler.clone();
+                let queue = queue.clone();
+                thread::spawn(move || work(&handler, &queue))
+            })
+            .collect();
+        Self {
+            seq: Cell::new(0),
+            queue,
+            workers,
+        }
+    }
+
+    /// Queue a hook run for the event. Events are numbered from 1 in the order submitted, dropped
+    /// ones included, so a hook seeing a gap in `GOSSIP_EVENT_SEQ` knows it missed some.
+    pub fn submit(&self, event: &Event) {
+        let seq = self.seq.get() + 1;
+        self.seq.set(seq);
+        self.queue.push(Job {
+            seq,
+            key: key(event),
+            event: event.clone(),
+        });
+    }
+}
+
+impl Drop for Hooks {
+    fn drop(&mut self) {
+        self.queue.close();
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.workers.iter().any(|worker| !worker.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for worker in self.workers.drain(..) {
+            if worker.is_finished() {
+                let _ = worker.join();
+            } else {
+                warn!(
+                    "event hook still running after {:?}, not waiting",
+                    SHUTDOWN_TIMEOUT
+                );
+            }
+        }
+    }
+}
+
+/// What `Overflow::Coalesce` treats as the same subject: the member for membership changes,
+/// otherwise the kind of event.
+fn key(event: &Event) -> String {
+    match event {
+        Event::Append(record)
+        | Event::Remove(record)
+        | Event::Rejoin {
+            current: record, ..
+        } => {
+            format!("{:?}", record.addr())
+        }
+        _ => event.kind().to_string(),
+    }
+}
+
+fn work(handler: &Handler, queue: &Queue) {
+    while let Some(job) = queue.pop() {
+        match handler {
+            Handler::Command { command, timeout } => match spawn(command, &job) {
+                Ok(child) => wait(child, &job, queue, *timeout),
+                Err(e) => warn!("event hook failed to start: {}", e),
+            },
+            Handler::Callback(callback) => callback(job.seq, &job.event),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+fn spawn(command: &str, job: &Job) -> std::io::Result<Child> {
+    shell(command)
+        .env("GOSSIP_EVENT_SEQ", job.seq.to_string())
+        .env("GOSSIP_EVENT_KIND", job.event.kind())
+        .env("GOSSIP_EVENT", format!("{:?}", job.event))
+        .stdin(Stdio::null())
+        .spawn()
+}
+
+/// Wait for the hook to exit, killing it after `timeout`, or `SHUTDOWN_TIMEOUT` after the queue
+/// was closed if that comes first.
+fn wait(mut child: Child, job: &Job, queue: &Queue, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let closed = queue.state.lock().unwrap().closed;
+        let deadline = closed.map_or(deadline, |closed| deadline.min(closed + SHUTDOWN_TIMEOUT));
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return,
+            Ok(Some(status)) => {
+                warn!("event hook exited with {} for: {:?}", status, job.event);
+                return;
+            }
+            Ok(None) if Instant::now() >= deadline => {
+                warn!("event hook timed out for: {:?}", job.event);
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                warn!("event hook wait failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(overflow: Overflow) -> Queue {
+        Queue {
+            state: Mutex::new(State::default()),
+            capacity: 2,
+            overflow,
+            ready: Condvar::new(),
+            space: Condvar::new(),
+        }
+    }
+
+    fn job(seq: u64) -> Job {
+        keyed(seq, &seq.to_string())
+    }
+
+    fn keyed(seq: u64, key: &str) -> Job {
+        Job {
+            seq,
+            key: key.to_string(),
+            event: Event::Safe,
+        }
+    }
+
+    fn seqs(queue: &Queue) -> Vec<u64> {
+        queue
+            .state
+            .lock()
+            .unwrap()
+            .jobs
+            .iter()
+            .map(|job| job.seq)
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let newest = queue(Overflow::DropNewest);
+        (1..=3).for_each(|seq| newest.push(job(seq)));
+        assert_eq!(seqs(&newest), vec![1, 2]);
+
+        let oldest = queue(Overflow::DropOldest);
+        (1..=3).for_each(|seq| oldest.push(job(seq)));
+        assert_eq!(seqs(&oldest), vec![2, 3]);
+
+        let block = Arc::new(queue(Overflow::Block));
+        (1..=2).for_each(|seq| block.push(job(seq)));
+        let pusher = {
+            let block = block.clone();
+            thread::spawn(move || block.push(job(3)))
+        };
+        thread::sleep(MAX_BLOCK / 5);
+        assert_eq!(block.pop().map(|job| job.seq), Some(1));
+        pusher.join().unwrap();
+        assert_eq!(seqs(&block), vec![2, 3]);
+
+        let start = Instant::now();
+        block.push(job(4));
+        assert!(start.elapsed() >= MAX_BLOCK);
+        assert_eq!(seqs(&block), vec![3, 4]);
+
+        let coalesce = queue(Overflow::Coalesce);
+        coalesce.push(keyed(1, "a"));
+        coalesce.push(keyed(2, "b"));
+        coalesce.push(keyed(3, "a"));
+        assert_eq!(seqs(&coalesce), vec![2, 3]);
+        coalesce.push(keyed(4, "c"));
+        assert_eq!(seqs(&coalesce), vec![3, 4]);
+
+        block.close();
+        assert!(block.pop().is_none());
+    }
+
+    #[test]
+    fn test_callback() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hooks = {
+            let seen = seen.clone();
+            Hooks::with_callback(
+                move |seq, event| seen.lock().unwrap().push((seq, event.kind())),
+                1,
+                8,
+                Overflow::DropNewest,
+            )
+        };
+        hooks.submit(&Event::Unsafe);
+        hooks.submit(&Event::Safe);
+        let start = Instant::now();
+        while seen.lock().unwrap().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(hooks);
+        assert_eq!(*seen.lock().unwrap(), vec![(1, "unsafe"), (2, "safe")]);
+    }
+
+    #[test]
+    fn test_shutdown_bounded() {
+        let (started, running) = std::sync::mpsc::channel();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, blocked) = (Mutex::new(started), Mutex::new(blocked));
+        let hooks = Hooks::with_callback(
+            move |_, _| {
+                started.lock().unwrap().send(()).unwrap();
+                let _ = blocked.lock().unwrap().recv();
+            },
+            1,
+            8,
+            Overflow::DropNewest,
+        );
+        hooks.submit(&Event::Unsafe);
+        running.recv().unwrap();
+        let start = Instant::now();
+        drop(hooks);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= SHUTDOWN_TIMEOUT && elapsed < SHUTDOWN_TIMEOUT * 2);
+        release.send(()).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use crate::agent::Agent;
+
+/// A change of this node's leadership, returned by `Leadership::update`.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Change {
+    Acquired { token: u64 },
+    Lost { token: u64 },
+}
+
+/// Gates work that must run on a single node, e.g. a scheduled job, on `Agent::is_leader`.
+///
+/// A node leads only while it is the agent's leader and knows at least one live peer
+/// (`Agent::is_ready`), so a node that is still joining, or has lost all of its peers, does
+/// not run the work on its own. Each term gets a fencing token: the time leadership was
+/// acquired, increasing across this node's terms. A resource that remembers the highest token
+/// it has seen can refuse a deposed leader that has not noticed yet, as long as the nodes'
+/// clocks differ by less than the fail cutoff.
+#[derive(Debug, Default)]
+pub struct Leadership {
+    token: Option<u64>,
+    last: u64,
+}
+
+impl Leadership {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follow the agent's leadership and report when this node starts or stops leading. Call
+    /// it after every batch of events the agent returns, not only on `Event::LeaderChanged`:
+    /// the first peer joining, or the last one leaving, changes it too.
+    pub fn update(&mut self, agent: &Agent, time: u64) -> Option<Change> {
+        let leading = agent.is_ready() && agent.is_leader();
+        match (leading, self.token) {
+            (true, None) => {
+                let token = time.max(self.last + 1);
+                self.last = token;
+                self.token = Some(token);
+                Some(Change::Acquired { token })
+            }
+            (false, Some(token)) => {
+                self.token = None;
+                Some(Change::Lost { token })
+            }
+            _ => None,
+        }
+    }
+
+    /// The fencing token of the current term, `None` while not leading.
+    pub fn token(&self) -> Option<u64> {
+        self.token
+    }
+
+    pub fn is_leading(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Run `job` with the current fencing token if this node leads, otherwise return `None`.
+    pub fn run<T, F: FnOnce(u64) -> T>(&self, job: F) -> Option<T> {
+        self.token.map(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Addr, Info, Message, Record};
+
+    const T: u64 = 1000000;
+
+    fn agent(i: u8, time: u64) -> Agent {
+        let addr = Addr::new(i as u32 * 0x01010101, i as u16);
+        Agent::new(Record::new(addr, time, 0), vec![], 1000, 5000)
+    }
+
+    fn ping(i: u8) -> Message {
+        Message::Ping(Info::new(Addr::new(i as u32 * 0x01010101, i as u16), 1))
+    }
+
+    #[test]
+    fn test_leadership() {
+        let mut leadership = Leadership::new();
+        let mut agent = agent(2, T);
+        assert_eq!(leadership.update(&agent, T), None);
+        assert_eq!(leadership.run(|token| token), None);
+
+        agent.accept(&ping(3), T + 10);
+        assert_eq!(
+            leadership.update(&agent, T + 10),
+            Some(Change::Acquired { token: T + 10 })
+        );
+        assert_eq!(leadership.update(&agent, T + 20), None);
+        assert_eq!(leadership.run(|token| token), Some(T + 10));
+
+        agent.accept(&ping(1), T + 30);
+        assert_eq!(
+            leadership.update(&agent, T + 30),
+            Some(Change::Lost { token: T + 10 })
+        );
+        assert!(!leadership.is_leading());
+
+        agent.accept(&ping(3), T + 5000);
+        agent.detect(T + 6040);
+        assert_eq!(
+            leadership.update(&agent, T + 6040),
+            Some(Change::Acquired { token: T + 6040 })
+        );
+    }
+}
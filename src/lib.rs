@@ -0,0 +1,9 @@
+pub mod agent;
+pub mod book;
+pub mod hooks;
+pub mod leader;
+pub mod snapshot;
+pub mod stats;
+pub mod testkit;
+pub mod topology;
+pub mod wire;
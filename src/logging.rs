@@ -0,0 +1,79 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use env_logger::fmt::{Target, WriteStyle};
+use env_logger::Builder;
+
+use gossip_peer::agent;
+
+use crate::rotate::RotatingFile;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+pub fn init(format: Format, file: Option<RotatingFile>) {
+    let mut builder = Builder::from_default_env();
+    if format == Format::Json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{{\"time\":{},\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"}}",
+                agent::get_current_millis(),
+                record.level(),
+                escape(record.target()),
+                escape(&record.args().to_string())
+            )
+        });
+    }
+    if let Some(file) = file {
+        builder
+            .target(Target::Pipe(Box::new(file)))
+            .write_style(WriteStyle::Never);
+    }
+    builder.init();
+}
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(
+            escape("say \"hi\"\n\tC:\\ \u{1}"),
+            "say \\\"hi\\\"\\n\\tC:\\\\ \\u0001"
+        );
+    }
+}
@@ -1,16 +1,77 @@
 use std::env;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{self, debug, info, trace};
+use log::{self, debug, info, trace, warn};
 
-mod agent;
-use agent::{Addr, Agent, Message, Record};
+use gossip_peer::agent::{self, Addr, Agent, Event, Message, Record};
+use gossip_peer::book::{self, AddressBook, Resolver};
+use gossip_peer::hooks::{Hooks, Overflow};
+use gossip_peer::topology::RandomFanout;
+
+mod anomaly;
+mod audit;
+mod corpus;
+mod daemon;
+mod doctor;
+mod logging;
+mod pacing;
+mod probe;
+mod protocol;
+mod quiet;
+mod rotate;
+mod selftest;
+mod transport;
+use anomaly::Anomalies;
+use audit::Audit;
+use corpus::Corpus;
+use quiet::QuietHours;
+use rotate::RotatingFile;
+use transport::Transport;
 
 fn main() {
-    env_logger::init();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let log_format: logging::Format = take_option(&mut args, "--log-format")
+        .map(|format| format.parse().expect("invalid log format"))
+        .unwrap_or(logging::Format::Text);
+    let log_rotate_hours: Option<u64> = take_option(&mut args, "--log-rotate-hours")
+        .map(|hours| hours.parse().expect("invalid log rotation hours"));
+    let log_file = take_option(&mut args, "--log-file").map(|path| {
+        let file = RotatingFile::open(path, rotate::MAX_SIZE, rotate::KEEP)
+            .expect("failed to open log file");
+        match log_rotate_hours {
+            Some(hours) => file.with_max_age(Duration::from_secs(hours * 3600)),
+            None => file,
+        }
+    });
+    logging::init(log_format, log_file);
+
+    if take_flag(&mut args, "--dump-protocol") {
+        println!("{}", protocol::json());
+        return;
+    }
+
+    match args.first().map(String::as_str) {
+        Some("doctor") => exit(doctor::run(&args[1..])),
+        Some("probe") => exit(probe::run(&args[1..])),
+        Some("dissector") => exit(protocol::run(&args[1..])),
+        Some("selftest") => exit(selftest::run(&args[1..])),
+        _ => (),
+    }
+
+    // Take the pidfile before forking, so a second instance fails while stderr is still open
+    let daemonize = take_flag(&mut args, "--daemonize");
+    let mut pidfile = take_option(&mut args, "--pidfile")
+        .map(|path| daemon::Pidfile::create(path).expect("failed to create pidfile"));
+    if daemonize {
+        daemon::daemonize().expect("daemonize failed");
+        if let Some(pidfile) = pidfile.as_mut() {
+            pidfile.update().expect("failed to update pidfile");
+        }
+    }
+
     let up = agent::get_current_millis();
     let mut tx = 0;
     let mut rx = 0;
@@ -20,90 +81,327 @@ fn main() {
     let ping_cutoff_millis: u64 = 1000;
     let fail_cutoff_millis: u64 = 5000;
     let gossip_interval_millis: u64 = (ping_cutoff_millis + fail_cutoff_millis) / 10;
+    let partition_threshold: f64 = 0.5;
+    let heal_threshold: usize = 2;
+    let anomaly_interval_millis: u64 = 60000;
+    let reclaim_millis: u64 = 24 * 3600 * 1000;
+    let summary_interval_millis: u64 = 60000;
+    let churn_threshold: usize = 10;
+    let churn_stretch: u64 = 2;
+    let quiet_slowdown: u64 = 4;
+    let skew_threshold_millis: u64 = 1000;
 
-    let args: Vec<String> = env::args().collect();
-    let host: u32 = 0;
-    let port: u16 = args[1].parse().unwrap();
+    let static_peers = take_flag(&mut args, "--static");
+    let trace_rounds = take_flag(&mut args, "--trace-rounds");
+    let warm_up_secs: u64 = take_option(&mut args, "--warm-up")
+        .map(|secs| secs.parse().expect("invalid warm-up"))
+        .unwrap_or(0);
+    let quiet_hours: Option<QuietHours> = take_option(&mut args, "--quiet-hours")
+        .map(|hours| hours.parse().expect("invalid quiet hours"));
+    let mut hidden = Vec::new();
+    while let Some(name) = take_option(&mut args, "--hidden") {
+        hidden.push(book::resolve(&name).expect("invalid hidden member"));
+    }
+    let fanout: Option<usize> =
+        take_option(&mut args, "--fanout").map(|n| positive("--fanout", &n));
+    let mut corpus = take_option(&mut args, "--corpus-dir")
+        .map(|dir| Corpus::open(dir).expect("failed to open corpus directory"));
+    let mut audit = take_option(&mut args, "--audit-log")
+        .map(|path| {
+            Audit::open(path, anomaly_interval_millis, up).expect("failed to open audit log")
+        });
+    let recv_budget: usize = take_option(&mut args, "--recv-budget")
+        .map(|n| positive("--recv-budget", &n))
+        .unwrap_or(64);
+    let hook_workers: usize = take_option(&mut args, "--hook-workers")
+        .map(|n| n.parse().expect("invalid hook workers"))
+        .unwrap_or(2);
+    let hook_timeout_secs: u64 = take_option(&mut args, "--hook-timeout")
+        .map(|secs| secs.parse().expect("invalid hook timeout"))
+        .unwrap_or(10);
+    let hook_queue: usize = take_option(&mut args, "--hook-queue")
+        .map(|n| n.parse().expect("invalid hook queue capacity"))
+        .unwrap_or(64);
+    let hook_overflow: Overflow = take_option(&mut args, "--hook-overflow")
+        .map(|policy| policy.parse().expect("invalid hook overflow policy"))
+        .unwrap_or(Overflow::DropNewest);
+    let hooks = take_option(&mut args, "--event-hook").map(|command| {
+        let timeout = Duration::from_secs(hook_timeout_secs);
+        Hooks::new(command, hook_workers, hook_queue, hook_overflow, timeout)
+    });
+    let host: u32 = take_option(&mut args, "--host")
+        .map(|ip| ip.parse::<Ipv4Addr>().expect("invalid host").into())
+        .unwrap_or(0);
+    let port: u16 = match args.first().map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => port,
+        Some(Err(e)) => usage(&format!("port {}: {}", args[0], e)),
+        None => usage("port: missing"),
+    };
 
-    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).expect("bind failed");
     let read_timeout_millis: u64 = gossip_interval_millis / 5;
-    socket
-        .set_read_timeout(Some(Duration::from_millis(read_timeout_millis)))
-        .expect("set read timeout failed");
+    let mut transport =
+        Transport::bind(port, Duration::from_millis(read_timeout_millis)).expect("bind failed");
+    let port = transport.port();
     info!("listening at :{}", port);
 
-    let seeds = args
-        .into_iter()
-        .skip(2)
-        .flat_map(|addr| addr.parse().ok())
-        .map(|addr: SocketAddr| addr.into())
-        .collect::<Vec<Addr>>();
+    let mut book = AddressBook::new(args.into_iter().skip(1).collect());
+    for (name, e) in book.resolve(&[]) {
+        warn!("failed to resolve seed {}: {}", name, e);
+    }
+    let seeds = book.addrs();
     debug!("seeds: {:?}", seeds);
+    let resolver = Resolver::spawn();
 
-    let addr = Addr { host, port };
-    let this = Record::new(addr, agent::get_current_millis(), 0);
-    let ping = Message::Ping(this.info()).bytes();
+    let addr = Addr::new(host, port);
+    let start = agent::get_current_millis();
+    let this = Record::new(addr, start, start);
 
-    let mut agent = Agent::new(this, seeds, ping_cutoff_millis, fail_cutoff_millis);
+    let mut agent = Agent::new(this, seeds, ping_cutoff_millis, fail_cutoff_millis)
+        .with_partition_detection(partition_threshold, fail_cutoff_millis)
+        .with_heal_detection(heal_threshold)
+        .with_churn_detection(churn_threshold, churn_stretch)
+        .with_safety_mode()
+        .with_dead_member_reclaim(reclaim_millis)
+        .with_warm_up(warm_up_secs * 1000)
+        .with_skew_detection(skew_threshold_millis);
+    if host == 0 {
+        agent = agent.with_local_hosts(transport::local_hosts());
+    }
+    if static_peers {
+        agent = agent.with_static_peers();
+    }
+    agent.set_names(book.names());
+    if trace_rounds {
+        agent = agent.with_decision_trace();
+    }
+    if !hidden.is_empty() {
+        agent = agent.with_hidden_members(hidden);
+    }
+    if let Some(fanout) = fanout {
+        agent = agent.with_topology(RandomFanout::new(fanout));
+    }
 
+    let mut anomalies = Anomalies::new(anomaly_interval_millis, up);
     let mut last_ping_millis: u64 = 0;
+    let mut last_slowdown: u64 = 1;
     let mut last_gossip_millis: u64 = 0;
-    let mut buf: [u8; 1024] = [0_u8; 1024];
+    let mut last_summary_millis: u64 = up;
+    let mut buf: [u8; agent::MAX_DATAGRAM] = [0_u8; agent::MAX_DATAGRAM];
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
     })
-    .expect("setting ctrl-c handler failed");
+    .expect("setting shutdown signal handler failed");
 
     while running.load(Ordering::SeqCst) {
         let now = agent::get_current_millis();
         agent.tick(now);
         trace!("loop: now={}", now);
+        transport.recover(Instant::now());
+        let slowdown = match quiet_hours {
+            Some(quiet) if quiet.contains(now) => quiet_slowdown,
+            _ => 1,
+        };
+        if slowdown != last_slowdown {
+            // Gossip less often only with cutoffs stretched to match, or live peers would
+            // fall out of the gossip targets and expire.
+            last_slowdown = slowdown;
+            info!("gossip slowed down {} times", slowdown);
+            agent.set_cutoffs(ping_cutoff_millis * slowdown, fail_cutoff_millis * slowdown);
+        }
 
-        if now - last_ping_millis >= ping_interval_millis {
+        if now - last_ping_millis >= ping_interval_millis * slowdown {
             last_ping_millis = now;
-            for addr in agent.ping() {
-                socket.send_to(&ping, addr.addr()).expect("send failed");
-                debug!("ping: {:?}", addr);
+            let ping = Message::Ping(agent.info()).bytes();
+            let params = agent.params(now).bytes();
+            let unreachable = agent.ping();
+            for addr in &unreachable {
+                let _ = transport.send_to(&ping, addr.addr());
+                let _ = transport.send_to(&params, addr.addr());
+                debug!("ping: {}", book.display(addr));
             }
+            resolver.request(book.pending(&unreachable, Instant::now()));
+        }
+
+        // Lookups run on the resolver thread; apply whatever has finished.
+        let resolved = book.addrs();
+        for (name, e) in book.update(resolver.results(), Instant::now()) {
+            debug!("failed to resolve seed {}: {}", name, e);
+        }
+        if book.addrs() != resolved {
+            info!("seeds resolved to {:?}", book.addrs());
+            agent.set_seeds(book.addrs());
+            agent.set_names(book.names());
         }
 
-        if let Ok((len, from)) = socket.recv_from(&mut buf) {
+        // Wait up to the read timeout for the first datagram, then take whatever else is
+        // already queued, up to the budget.
+        for i in 0..recv_budget {
+            if i == 1 {
+                transport.set_nonblocking(true);
+            }
+            let (len, from) = match transport.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => break,
+            };
             rx += len;
+            let message = Message::parse(&buf[..len]);
+            if let Some(corpus) = corpus.as_mut() {
+                corpus.record(&buf[..len], message.as_ref());
+            }
             let addr: Addr = from.into();
-            if let Some(mut message) = Message::parse(&buf[0..len]) {
-                message.patch(addr);
+            if !agent.is_allowed(&addr) {
+                debug!("rejected message from {:?}", addr);
+                anomalies.record("denied", addr);
+                if let Some(audit) = audit.as_mut() {
+                    audit.deny(&addr);
+                }
+            } else if let Some(message) = message {
+                if let Message::Echo(nonce) = message {
+                    let reply = Message::EchoReply(nonce).bytes();
+                    tx += transport.send_to(&reply, from).unwrap_or(0);
+                    continue;
+                }
                 debug!("message from {:?}: {:?}", addr, message);
-                let events = agent.accept(&message, now);
-                for e in events {
-                    info!("event: {:?}", e);
+                let events = agent.accept_from(addr, &message, now);
+                // Answer a newly noticed mismatch with our own parameters so the peer sees it
+                // too; each side reports a peer once, which ends the exchange.
+                let mismatch = events
+                    .iter()
+                    .any(|e| matches!(e, Event::Incompatible { .. }));
+                report(events, &mut audit, &hooks);
+                if mismatch {
+                    tx += transport.send_to(&agent.params(now).bytes(), from).unwrap_or(0);
                 }
+            } else {
+                anomalies.record("malformed", addr);
             }
         }
+        transport.set_nonblocking(false);
+        anomalies.flush(now);
+        if let Some(audit) = audit.as_mut() {
+            audit.flush(now);
+        }
 
-        if now - last_gossip_millis >= gossip_interval_millis && agent.is_ready() {
+        let mut outbox = Vec::new();
+        let sync = agent.take_sync();
+        let gossip_due = now - last_gossip_millis >= gossip_interval_millis * slowdown;
+        if (sync || gossip_due) && agent.is_ready() {
             last_gossip_millis = now;
             for (addr, message) in agent.gossip(now) {
                 debug!("gossip for peer {:?}: {:?}", addr, message);
-                let bytes = message.bytes();
-                tx += bytes.len();
-                socket
-                    .send_to(&bytes, addr.addr())
-                    .expect("failed to send");
+                outbox.push((addr, message.bytes()));
+            }
+            if let Some(trace) = agent.last_trace() {
+                info!("round: {:?}", trace);
             }
         }
+        for (addr, message) in agent.take_params(now) {
+            outbox.push((addr, message.bytes()));
+        }
 
-        let delay_millis = gossip_interval_millis / 2 - (agent::get_current_millis() - now);
+        let elapsed_millis = agent::get_current_millis().saturating_sub(now);
+        let delay_millis = (gossip_interval_millis / 2).saturating_sub(elapsed_millis);
         trace!("delay: {} ms", delay_millis);
-        std::thread::sleep(Duration::from_millis(delay_millis));
+        tx += pacing::send(&outbox, Duration::from_millis(delay_millis), |addr, bytes| {
+            transport.send_to(bytes, addr.addr())
+        });
+        report(transport.take_events(), &mut audit, &hooks);
+
+        report(agent.detect(now), &mut audit, &hooks);
 
-        let events = agent.detect(now);
-        for e in events {
-            info!("event: {:?}", e);
+        if now - last_summary_millis >= summary_interval_millis {
+            last_summary_millis = now;
+            summary(&agent, &book);
         }
     }
 
+    info!("stats: {:?}", agent.stats());
     println!("\nup: {}\ntx: {}\nrx: {}", (agent::get_current_millis() - up) / 1000, tx, rx);
+    for (kind, count) in anomalies.totals() {
+        println!("{}: {}", kind, count);
+    }
+}
+
+fn exit(ok: bool) -> ! {
+    std::process::exit(if ok { 0 } else { 1 })
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    eprintln!("usage: gossip-peer [options] <port> [seed...]");
+    exit(false)
+}
+
+/// The value of an option that must be a number of at least 1, checked like `doctor` does.
+fn positive(name: &str, value: &str) -> usize {
+    match value.parse() {
+        Ok(n) if n >= 1 => n,
+        Ok(_) => usage(&format!("{} {}: must be at least 1", name, value)),
+        Err(e) => usage(&format!("{} {}: {}", name, value, e)),
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let at = args.iter().position(|arg| arg == name)?;
+    args.remove(at);
+    if at < args.len() {
+        Some(args.remove(at))
+    } else {
+        None
+    }
+}
+
+fn summary(agent: &Agent, book: &AddressBook) {
+    let stats = agent.stats();
+    for (kind, counter) in &stats.sent {
+        info!("sent {}: {} messages, {} bytes", kind, counter.count, counter.bytes);
+    }
+    for (kind, counter) in &stats.received {
+        info!("received {}: {} messages, {} bytes", kind, counter.count, counter.bytes);
+    }
+    for (addr, traffic) in &stats.peers {
+        let skew = agent
+            .skew(addr)
+            .map(|skew| format!(", clock skew {} ms", skew))
+            .unwrap_or_default();
+        info!(
+            "peer {}: sent {} bytes, received {} bytes{}",
+            book.display(addr),
+            traffic.sent.bytes,
+            traffic.received.bytes,
+            skew
+        );
+    }
+}
+
+fn report(events: Vec<Event>, audit: &mut Option<Audit>, hooks: &Option<Hooks>) {
+    for e in events {
+        if let Some(hooks) = hooks.as_ref() {
+            hooks.submit(&e);
+        }
+        match e {
+            Event::Reject(addr) => {
+                warn!("event: {:?}", e);
+                if let Some(audit) = audit.as_mut() {
+                    audit.record("reject", &addr);
+                }
+            }
+            Event::PossiblePartition { .. }
+            | Event::Unsafe
+            | Event::TransportDown
+            | Event::HighChurn { .. }
+            | Event::Incompatible { .. }
+            | Event::ClockSkew { .. } => warn!("event: {:?}", e),
+            _ => info!("event: {:?}", e),
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gossip_peer::agent::Addr;
+
+/// Longest window `send` spreads datagrams over, whatever the caller asks for.
+pub const MAX_WINDOW: Duration = Duration::from_secs(1);
+
+/// Send datagrams spread evenly over `window` (at most `MAX_WINDOW`) rather than back to back,
+/// then sleep out the rest of it. Returns the number of bytes sent; datagrams that fail to send
+/// are skipped.
+pub fn send<F>(datagrams: &[(Addr, Vec<u8>)], window: Duration, mut send: F) -> usize
+where
+    F: FnMut(Addr, &[u8]) -> io::Result<usize>,
+{
+    let window = window.min(MAX_WINDOW);
+    let start = Instant::now();
+    let mut sent = 0;
+    for ((addr, bytes), offset) in datagrams.iter().zip(schedule(datagrams.len(), window)) {
+        sleep_until(start + offset);
+        sent += send(*addr, bytes).unwrap_or(0);
+    }
+    sleep_until(start + window);
+    sent
+}
+
+fn schedule(count: usize, window: Duration) -> Vec<Duration> {
+    (0..count as u32)
+        .map(|i| window * i / count as u32)
+        .collect()
+}
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now {
+        thread::sleep(deadline - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let window = Duration::from_millis(300);
+        assert!(schedule(0, window).is_empty());
+        assert_eq!(schedule(1, window), vec![Duration::ZERO]);
+        assert_eq!(
+            schedule(3, window),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_window_capped() {
+        let start = Instant::now();
+        let sent = send(&[], Duration::from_secs(u64::MAX), |_, _| Ok(0));
+        assert_eq!(sent, 0);
+        assert!(start.elapsed() < MAX_WINDOW * 2);
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use gossip_peer::agent::{self, Message};
+
+/// Echo requests sent per size by `probe_mtu` before the size is taken not to fit.
+#[cfg(target_os = "linux")]
+const MTU_TRIES: usize = 2;
+
+pub fn probe(addr: SocketAddr, timeout: Duration) -> io::Result<Duration> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    echo(&socket, addr, Message::Echo(0).size(), timeout)
+}
+
+/// The largest datagram, up to `MAX_DATAGRAM` bytes, that reaches `addr` unfragmented: echo
+/// requests padded to a binary-searched size are sent with don't-fragment set, so one that
+/// the path would have to fragment is refused locally or dropped on the way and not answered.
+#[cfg(target_os = "linux")]
+pub fn probe_mtu(addr: SocketAddr, timeout: Duration) -> io::Result<usize> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    socket.connect(addr)?;
+    dont_fragment(&socket)?;
+    let fits = |len: usize| -> io::Result<bool> {
+        for _ in 0..MTU_TRIES {
+            match echo(&socket, addr, len, timeout) {
+                Ok(_) => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    };
+    let (mut fit, mut unfit) = (Message::Echo(0).size(), agent::MAX_DATAGRAM + 1);
+    if !fits(fit)? {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "no echo reply"));
+    }
+    while unfit - fit > 1 {
+        let len = fit + (unfit - fit) / 2;
+        if fits(len)? {
+            fit = len;
+        } else {
+            unfit = len;
+        }
+    }
+    Ok(fit)
+}
+
+/// Set don't-fragment on the socket's datagrams and have sends larger than the MTU the kernel
+/// knows for the route fail with `EMSGSIZE`.
+#[cfg(target_os = "linux")]
+fn dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value: libc::c_int = libc::IP_PMTUDISC_DO;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send an echo request padded with zeros to `len` bytes and wait for its reply.
+fn echo(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    len: usize,
+    timeout: Duration,
+) -> io::Result<Duration> {
+    let nonce = nonce();
+    let mut request = Message::Echo(nonce).bytes();
+    request.resize(len.max(request.len()), 0);
+    let start = Instant::now();
+    socket.send_to(&request, addr)?;
+
+    let mut buf = [0_u8; agent::MAX_DATAGRAM];
+    loop {
+        let remaining = timeout
+            .checked_sub(start.elapsed())
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no echo reply"))?;
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no echo reply"))
+            }
+            Err(e) => return Err(e),
+        };
+        if from == addr && Message::parse(&buf[..len]) == Some(Message::EchoReply(nonce)) {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+fn nonce() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+pub fn run(args: &[String]) -> bool {
+    let addr: SocketAddr = match args.first().map(|addr| addr.parse()) {
+        Some(Ok(addr)) => addr,
+        _ => {
+            println!("usage: gossip-peer probe <ip:port> [count]");
+            return false;
+        }
+    };
+    let count: usize = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(3);
+    let mut replies = 0;
+    for _ in 0..count {
+        match probe(addr, Duration::from_secs(1)) {
+            Ok(rtt) => {
+                replies += 1;
+                println!("reply from {}: rtt={:.3} ms", addr, rtt.as_secs_f64() * 1000.0);
+            }
+            Err(e) => println!("no reply from {}: {}", addr, e),
+        }
+    }
+    println!("{}/{} replies", replies, count);
+    #[cfg(target_os = "linux")]
+    {
+        if replies > 0 {
+            match probe_mtu(addr, Duration::from_secs(1)) {
+                Ok(len) => println!(
+                    "datagrams of up to {} bytes reach {} unfragmented",
+                    len, addr
+                ),
+                Err(e) => println!("path MTU to {} unknown: {}", addr, e),
+            }
+        }
+    }
+    replies > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_probe_mtu() {
+        let peer = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = peer.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0_u8; agent::MAX_DATAGRAM];
+            while let Ok((len, from)) = peer.recv_from(&mut buf) {
+                if let Some(Message::Echo(nonce)) = Message::parse(&buf[..len]) {
+                    let _ = peer.send_to(&Message::EchoReply(nonce).bytes(), from);
+                }
+            }
+        });
+        assert_eq!(probe_mtu(addr, Duration::from_secs(1)).unwrap(), agent::MAX_DATAGRAM);
+    }
+}
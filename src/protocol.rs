@@ -0,0 +1,221 @@
+use gossip_peer::agent;
+use gossip_peer::wire::{self, Field, Layout, Type};
+
+/// Machine-readable description of the wire protocol, rendered from `wire::MESSAGES`.
+pub fn json() -> String {
+    let structs: Vec<String> = wire::STRUCTS.iter().map(layout_json).collect();
+    let messages: Vec<String> = wire::MESSAGES.iter().map(layout_json).collect();
+    format!(
+        "{{\"transport\":\"udp\",\"endianness\":\"big\",\"max_datagram\":{},\"code\":\"u8\",\"structs\":[{}],\"messages\":[{}]}}",
+        agent::MAX_DATAGRAM,
+        structs.join(","),
+        messages.join(",")
+    )
+}
+
+fn layout_json(layout: &Layout) -> String {
+    let fields: Vec<String> = layout.fields.iter().map(field_json).collect();
+    let code = layout
+        .code
+        .map(|code| format!("\"code\":{},", code))
+        .unwrap_or_default();
+    format!(
+        "{{\"name\":\"{}\",{}\"fields\":[{}]}}",
+        layout.name,
+        code,
+        fields.join(",")
+    )
+}
+
+fn field_json(field: &Field) -> String {
+    match field.ty {
+        Type::Repeated(name, count) => format!(
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"count\":\"{}\"}}",
+            field.name, name, count
+        ),
+        ty => format!(
+            "{{\"name\":\"{}\",\"type\":\"{}\"}}",
+            field.name,
+            type_name(ty)
+        ),
+    }
+}
+
+fn type_name(ty: Type) -> &'static str {
+    match ty {
+        Type::U16 => "u16",
+        Type::U32 => "u32",
+        Type::U64 => "u64",
+        Type::Struct(name) | Type::Repeated(name, _) => name,
+    }
+}
+
+/// Wireshark Lua dissector for the protocol on the given UDP ports, rendered from
+/// `wire::MESSAGES`. Load it with `wireshark -X lua_script:gossip.lua`.
+pub fn dissector(ports: &[u16]) -> String {
+    let layouts = || wire::STRUCTS.iter().chain(wire::MESSAGES.iter());
+    let mut lua = String::new();
+    lua.push_str("-- generated by `gossip-peer dissector`, do not edit\n");
+    lua.push_str("local proto = Proto(\"gossip\", \"Gossip peer\")\n\n");
+
+    let codes: Vec<String> = wire::MESSAGES
+        .iter()
+        .filter_map(|layout| Some(format!("[{}] = \"{}\"", layout.code?, layout.name)))
+        .collect();
+    lua.push_str(&format!(
+        "local f_code = ProtoField.uint8(\"gossip.code\", \"code\", base.DEC, {{ {} }})\n",
+        codes.join(", ")
+    ));
+    let mut fields = vec!["f_code".to_string()];
+    for layout in layouts() {
+        for field in layout.fields {
+            let constructor = match field.ty {
+                Type::U16 => "uint16",
+                Type::U32 if field.name == "host" => "ipv4",
+                Type::U32 => "uint32",
+                Type::U64 => "uint64",
+                Type::Struct(_) | Type::Repeated(_, _) => continue,
+            };
+            let var = format!("f_{}_{}", layout.name, field.name);
+            lua.push_str(&format!(
+                "local {} = ProtoField.{}(\"gossip.{}.{}\", \"{}\")\n",
+                var, constructor, layout.name, field.name, field.name
+            ));
+            fields.push(var);
+        }
+    }
+    lua.push_str(&format!("proto.fields = {{ {} }}\n", fields.join(", ")));
+
+    for layout in layouts() {
+        lua.push_str(&format!(
+            "\nlocal function dissect_{}(buffer, offset, tree)\n",
+            layout.name
+        ));
+        if layout.code.is_none() {
+            lua.push_str(&format!(
+                "    tree = tree:add(buffer(offset, {}), \"{}\")\n",
+                fixed_len(layout),
+                layout.name
+            ));
+        }
+        for field in layout.fields {
+            match field.ty {
+                Type::Struct(name) => lua.push_str(&format!(
+                    "    offset = dissect_{}(buffer, offset, tree)\n",
+                    name
+                )),
+                Type::Repeated(name, count) => lua.push_str(&format!(
+                    "    for _ = 1, {} do\n        offset = dissect_{}(buffer, offset, tree)\n    end\n",
+                    count, name
+                )),
+                ty => {
+                    let len = width(ty);
+                    lua.push_str(&format!(
+                        "    tree:add(f_{}_{}, buffer(offset, {}))\n",
+                        layout.name, field.name, len
+                    ));
+                    let counts = layout.fields.iter().any(
+                        |other| matches!(other.ty, Type::Repeated(_, count) if count == field.name),
+                    );
+                    if counts {
+                        lua.push_str(&format!(
+                            "    local {} = buffer(offset, {}):uint()\n",
+                            field.name, len
+                        ));
+                    }
+                    lua.push_str(&format!("    offset = offset + {}\n", len));
+                }
+            }
+        }
+        lua.push_str("    return offset\nend\n");
+    }
+
+    let dispatch: Vec<String> = wire::MESSAGES
+        .iter()
+        .filter_map(|layout| Some(format!("[{}] = dissect_{}", layout.code?, layout.name)))
+        .collect();
+    lua.push_str(&format!(
+        "\nlocal messages = {{ {} }}\n\n",
+        dispatch.join(", ")
+    ));
+    lua.push_str(concat!(
+        "function proto.dissector(buffer, pinfo, tree)\n",
+        "    pinfo.cols.protocol = \"GOSSIP\"\n",
+        "    local subtree = tree:add(proto, buffer())\n",
+        "    subtree:add(f_code, buffer(0, 1))\n",
+        "    local dissect = messages[buffer(0, 1):uint()]\n",
+        "    if dissect then\n",
+        "        dissect(buffer, 1, subtree)\n",
+        "    end\n",
+        "end\n\n",
+        "local udp = DissectorTable.get(\"udp.port\")\n",
+    ));
+    for port in ports {
+        lua.push_str(&format!("udp:add({}, proto)\n", port));
+    }
+    lua
+}
+
+pub fn run(args: &[String]) -> bool {
+    let ports: Result<Vec<u16>, _> = args.iter().map(|port| port.parse()).collect();
+    match ports {
+        Ok(ports) if ports.is_empty() => print!("{}", dissector(&[12000])),
+        Ok(ports) => print!("{}", dissector(&ports)),
+        Err(e) => {
+            eprintln!("invalid port: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+fn width(ty: Type) -> usize {
+    match ty {
+        Type::U16 => 2,
+        Type::U32 => 4,
+        Type::U64 => 8,
+        Type::Struct(_) | Type::Repeated(_, _) => 0,
+    }
+}
+
+fn fixed_len(layout: &Layout) -> usize {
+    layout.fields.iter().map(|field| width(field.ty)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let json = json();
+        assert!(json.starts_with("{\"transport\":\"udp\",\"endianness\":\"big\""));
+        assert!(json.contains(
+            "{\"name\":\"info\",\"fields\":[{\"name\":\"host\",\"type\":\"u32\"},{\"name\":\"port\",\"type\":\"u16\"},{\"name\":\"beat\",\"type\":\"u64\"}]}"
+        ));
+        assert!(json.contains(
+            "{\"name\":\"list\",\"code\":1,\"fields\":[{\"name\":\"count\",\"type\":\"u32\"},{\"name\":\"infos\",\"type\":\"info\",\"count\":\"count\"}]}"
+        ));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+
+    #[test]
+    fn test_dissector() {
+        let lua = dissector(&[12000, 12001]);
+        assert!(
+            lua.contains("[2] = \"echo\", [3] = \"echo_reply\", [4] = \"params\" }")
+        );
+        assert!(
+            lua.contains("local f_info_host = ProtoField.ipv4(\"gossip.info.host\", \"host\")\n")
+        );
+        assert!(lua.contains("    tree = tree:add(buffer(offset, 14), \"info\")\n"));
+        assert!(lua.contains(
+            "    for _ = 1, count do\n        offset = dissect_info(buffer, offset, tree)\n"
+        ));
+        assert!(lua.ends_with("udp:add(12000, proto)\nudp:add(12001, proto)\n"));
+        assert_eq!(
+            lua.matches("function").count(),
+            lua.matches("\nend\n").count()
+        );
+    }
+}
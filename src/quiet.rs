@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+const HOUR_MILLIS: u64 = 3600 * 1000;
+
+/// Daily window of whole UTC hours, `start` inclusive and `end` exclusive, that may wrap past
+/// midnight (`22-6`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u64,
+    end: u64,
+}
+
+impl QuietHours {
+    pub fn contains(&self, millis: u64) -> bool {
+        let hour = millis / HOUR_MILLIS % 24;
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hour = |h: &str| match h.parse::<u64>() {
+            Ok(h) if h < 24 => Ok(h),
+            _ => Err(format!("invalid hour: {}", h)),
+        };
+        match s.split_once('-') {
+            Some((start, end)) => Ok(QuietHours {
+                start: hour(start)?,
+                end: hour(end)?,
+            }),
+            None => Err(format!("expected <start>-<end> hours: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours() {
+        let at = |hour: u64| 20000 * 24 * HOUR_MILLIS + hour * HOUR_MILLIS + 1;
+
+        let night: QuietHours = "22-6".parse().unwrap();
+        assert!(night.contains(at(23)));
+        assert!(night.contains(at(0)));
+        assert!(night.contains(at(5)));
+        assert!(!night.contains(at(6)));
+        assert!(!night.contains(at(12)));
+
+        let lunch: QuietHours = "12-13".parse().unwrap();
+        assert!(lunch.contains(at(12)));
+        assert!(!lunch.contains(at(13)));
+        assert!(!lunch.contains(at(11)));
+
+        assert!("24-6".parse::<QuietHours>().is_err());
+        assert!("22".parse::<QuietHours>().is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+pub const MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const KEEP: usize = 5;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_age: Option<Duration>,
+    opened: Instant,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_age: None,
+            opened: Instant::now(),
+            keep,
+        })
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn is_full(&self, len: usize) -> bool {
+        let expired = self
+            .max_age
+            .map(|age| self.opened.elapsed() >= age)
+            .unwrap_or(false);
+        self.size > 0 && (expired || self.size + len as u64 > self.max_size)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.keep).rev() {
+            let from = self.backup(i);
+            if from.exists() {
+                fs::rename(from, self.backup(i + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.backup(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn backup(&self, i: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", i));
+        name.into()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_full(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("gossip-peer-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+
+        let mut file = RotatingFile::open(&path, 8, 2).unwrap();
+        for line in &["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddd\n");
+        assert_eq!(fs::read_to_string(dir.join("test.log.1")).unwrap(), "cccccc\n");
+        assert_eq!(fs::read_to_string(dir.join("test.log.2")).unwrap(), "bbbbbb\n");
+        assert!(!dir.join("test.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let dir = std::env::temp_dir().join(format!("gossip-peer-age-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+
+        let mut file = RotatingFile::open(&path, MAX_SIZE, 1)
+            .unwrap()
+            .with_max_age(Duration::from_millis(0));
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(dir.join("test.log.1")).unwrap(), "first\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
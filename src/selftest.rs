@@ -0,0 +1,75 @@
+use gossip_peer::agent::Event;
+use gossip_peer::testkit::Cluster;
+
+use crate::doctor::report;
+
+const PING_CUTOFF: u64 = 200;
+const FAIL_CUTOFF: u64 = 500;
+const TIMEOUT: u64 = 10 * (PING_CUTOFF + FAIL_CUTOFF);
+
+/// Run three peers on loopback through a join, a crash and a rejoin, printing each outcome.
+pub fn run(_args: &[String]) -> bool {
+    let mut cluster = match Cluster::udp(3, PING_CUTOFF, FAIL_CUTOFF) {
+        Ok(cluster) => cluster,
+        Err(e) => return report("bind", Err(format!("cannot bind loopback: {}", e))),
+    };
+    let crashed = cluster.addr(2);
+
+    let ok = report(
+        "join",
+        cluster
+            .run_until(TIMEOUT, |cluster| {
+                (0..3).all(|i| cluster.members(i).len() == 3)
+            })
+            .map(|ms| format!("3 nodes converged in {} ms", ms))
+            .ok_or(format!("3 nodes did not converge in {} ms", TIMEOUT)),
+    );
+    if !ok {
+        return false;
+    }
+
+    cluster.crash(2);
+    let ok = report(
+        "failure",
+        cluster
+            .run_until(TIMEOUT, |cluster| {
+                (0..2).all(|i| {
+                    cluster
+                        .events(i)
+                        .iter()
+                        .any(|e| matches!(e, Event::Remove(r) if r.addr() == crashed))
+                })
+            })
+            .map(|ms| format!("stopped node detected by both peers in {} ms", ms))
+            .ok_or(format!("stopped node not detected in {} ms", TIMEOUT)),
+    );
+    if !ok {
+        return false;
+    }
+
+    cluster.recover(2);
+    report(
+        "rejoin",
+        cluster
+            .run_until(TIMEOUT, |cluster| {
+                let rejoined = |i| {
+                    cluster.events(i).iter().any(
+                        |e| matches!(e, Event::Rejoin { current, .. } if current.addr() == crashed),
+                    )
+                };
+                (0..2).all(rejoined) && cluster.members(2).len() == 3
+            })
+            .map(|ms| format!("resumed node rejoined in {} ms", ms))
+            .ok_or(format!("resumed node did not rejoin in {} ms", TIMEOUT)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest() {
+        assert!(run(&[]));
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::agent::Addr;
+
+/// Live membership at the time of the last change: this node and its live peers ordered by
+/// address, as in `Agent::members_sorted`, and the names given to the members that have one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub version: u64,
+    pub members: Vec<Addr>,
+    pub names: Vec<(Addr, String)>,
+}
+
+impl Snapshot {
+    pub fn name(&self, addr: &Addr) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(a, _)| a == addr)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+/// Shared, lock-free view of the latest `Snapshot`, cheap to clone and read from any thread.
+#[derive(Debug, Clone)]
+pub struct Snapshots(Arc<ArcSwap<Snapshot>>);
+
+impl Snapshots {
+    pub(crate) fn new(members: Vec<Addr>) -> Self {
+        let snapshot = Snapshot {
+            version: 0,
+            members,
+            names: vec![],
+        };
+        Self(Arc::new(ArcSwap::from_pointee(snapshot)))
+    }
+
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.0.load_full()
+    }
+
+    pub(crate) fn publish(&self, members: Vec<Addr>, names: Vec<(Addr, String)>) {
+        let version = self.0.load().version + 1;
+        self.0.store(Arc::new(Snapshot {
+            version,
+            members,
+            names,
+        }));
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+
+use crate::agent::{Addr, Message};
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Counter {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl Counter {
+    fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Traffic {
+    pub sent: Counter,
+    pub received: Counter,
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Stats {
+    pub rounds: u64,
+    pub sent: BTreeMap<&'static str, Counter>,
+    pub received: BTreeMap<&'static str, Counter>,
+    pub peers: BTreeMap<Addr, Traffic>,
+    pub events: BTreeMap<&'static str, u64>,
+    pub alive: usize,
+    pub down: usize,
+}
+
+impl Stats {
+    pub(crate) fn on_send(&mut self, to: Addr, message: &Message) {
+        let size = message.size();
+        self.sent.entry(message.kind()).or_default().add(size);
+        self.peers.entry(to).or_default().sent.add(size);
+    }
+
+    pub(crate) fn on_receive(&mut self, from: Option<Addr>, message: &Message) {
+        let size = message.size();
+        self.received.entry(message.kind()).or_default().add(size);
+        if let Some(from) = from {
+            self.peers.entry(from).or_default().received.add(size);
+        }
+    }
+}
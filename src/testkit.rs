@@ -0,0 +1,473 @@
+//! In-process virtual cluster for tests: agents exchange messages in memory (or over loopback
+//! UDP, see `Cluster::udp`) on a virtual clock, and the test decides when time passes, which
+//! nodes crash, where the network splits and which messages are lost.
+//!
+//! ```
+//! use gossip_peer::testkit::Cluster;
+//!
+//! let mut cluster = Cluster::new(3, 1000, 5000);
+//! cluster.run(1000);
+//! assert!(cluster.is_converged());
+//!
+//! cluster.crash(2);
+//! cluster.run(10000);
+//! assert_eq!(cluster.members(0), vec![cluster.addr(0), cluster.addr(1)]);
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+
+use crate::agent::{Addr, Agent, Event, Message, Record, MAX_DATAGRAM};
+
+/// Virtual time the cluster starts at.
+pub const START: u64 = 1000000000;
+
+const BASE_PORT: u16 = 10000;
+
+struct Node {
+    addr: Addr,
+    socket: Option<UdpSocket>,
+    agent: Agent,
+    up: bool,
+    group: usize,
+    drop: usize,
+    events: Vec<Event>,
+}
+
+pub struct Cluster {
+    nodes: Vec<Node>,
+    blackholes: Vec<(usize, usize)>,
+    time: u64,
+    step: u64,
+}
+
+impl Cluster {
+    /// `n` nodes on 127.0.0.1 with consecutive ports, all seeded with node 0. Each step
+    /// advances the clock by a tenth of the total cutoff, the binary's gossip interval.
+    pub fn new(n: usize, ping_cutoff: u64, fail_cutoff: u64) -> Cluster {
+        let nodes = (0..n).map(|i| (addr(i), None)).collect();
+        Cluster::start(nodes, ping_cutoff, fail_cutoff)
+    }
+
+    /// Like `new`, with every node on its own loopback UDP socket (on a free port), so that
+    /// messages go through the encoder, the kernel and the decoder. Partitions and crashes
+    /// still apply.
+    pub fn udp(n: usize, ping_cutoff: u64, fail_cutoff: u64) -> io::Result<Cluster> {
+        let nodes = (0..n)
+            .map(|_| {
+                let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+                socket.set_nonblocking(true)?;
+                Ok((socket.local_addr()?.into(), Some(socket)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Cluster::start(nodes, ping_cutoff, fail_cutoff))
+    }
+
+    fn start(nodes: Vec<(Addr, Option<UdpSocket>)>, ping_cutoff: u64, fail_cutoff: u64) -> Cluster {
+        let seed = nodes.first().map(|(addr, _)| *addr);
+        let nodes = nodes
+            .into_iter()
+            .map(|(addr, socket)| {
+                let seeds = seed.into_iter().filter(|seed| *seed != addr).collect();
+                let this = Record::new(addr, START, START);
+                Node {
+                    addr,
+                    socket,
+                    agent: Agent::new(this, seeds, ping_cutoff, fail_cutoff),
+                    up: true,
+                    group: 0,
+                    drop: 0,
+                    events: vec![],
+                }
+            })
+            .collect();
+        Cluster {
+            nodes,
+            blackholes: vec![],
+            time: START,
+            step: ((ping_cutoff + fail_cutoff) / 10).max(1),
+        }
+    }
+
+    /// Apply agent options to every node, e.g. `|agent| agent.with_safety_mode()`.
+    pub fn configure<F>(mut self, f: F) -> Cluster
+    where
+        F: Fn(Agent) -> Agent,
+    {
+        self.nodes = self
+            .nodes
+            .into_iter()
+            .map(|node| Node {
+                agent: f(node.agent),
+                ..node
+            })
+            .collect();
+        self
+    }
+
+    pub fn addr(&self, i: usize) -> Addr {
+        self.nodes[i].addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn agent(&self, i: usize) -> &Agent {
+        &self.nodes[i].agent
+    }
+
+    pub fn agent_mut(&mut self, i: usize) -> &mut Agent {
+        &mut self.nodes[i].agent
+    }
+
+    /// Every event node `i` has emitted so far.
+    pub fn events(&self, i: usize) -> &[Event] {
+        &self.nodes[i].events
+    }
+
+    /// Addresses of the members node `i` sees alive, itself included, sorted.
+    pub fn members(&self, i: usize) -> Vec<Addr> {
+        self.nodes[i]
+            .agent
+            .members_sorted()
+            .iter()
+            .map(Record::addr)
+            .collect()
+    }
+
+    /// Whether every running node sees exactly the running nodes it can reach, directly or
+    /// through others.
+    pub fn is_converged(&self) -> bool {
+        (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].up)
+            .all(|i| {
+                let mut reachable: Vec<Addr> = self
+                    .reachable(i)
+                    .into_iter()
+                    .map(|j| self.addr(j))
+                    .collect();
+                reachable.sort();
+                self.members(i) == reachable
+            })
+    }
+
+    /// Stop node `i`: it neither runs nor receives until `recover`.
+    pub fn crash(&mut self, i: usize) {
+        self.nodes[i].up = false;
+    }
+
+    /// Resume node `i` with the state it had when it crashed.
+    pub fn recover(&mut self, i: usize) {
+        self.nodes[i].up = true;
+    }
+
+    /// Split the network: nodes in different groups cannot reach each other, and nodes left
+    /// out of every group form one more group.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        for node in self.nodes.iter_mut() {
+            node.group = 0;
+        }
+        for (g, group) in groups.iter().enumerate() {
+            for &i in group.iter() {
+                self.nodes[i].group = g + 1;
+            }
+        }
+    }
+
+    /// Cut the link between nodes `i` and `j` in both directions, leaving the rest of the
+    /// network as it is, so they only hear about each other through other nodes.
+    pub fn blackhole(&mut self, i: usize, j: usize) {
+        self.blackholes.push((i, j));
+    }
+
+    /// Undo `partition` and `blackhole`.
+    pub fn heal(&mut self) {
+        self.blackholes.clear();
+        self.partition(&[]);
+    }
+
+    /// Lose the next `n` messages node `i` sends, whoever they are for; 0 stops dropping.
+    pub fn drop_next(&mut self, i: usize, n: usize) {
+        self.nodes[i].drop = n;
+    }
+
+    /// Make node `i` declare node `j` failed now, see `Agent::force_remove`.
+    pub fn suspect(&mut self, i: usize, j: usize) {
+        let addr = self.addr(j);
+        let node = &mut self.nodes[i];
+        let events = node.agent.force_remove(&addr, self.time);
+        node.events.extend(events);
+    }
+
+    /// Move the clock forward by `millis` without running any node, as if the whole cluster
+    /// had been paused, e.g. by a suspended VM. The next step sees the jump.
+    pub fn advance(&mut self, millis: u64) {
+        self.time += millis;
+    }
+
+    /// Run steps until at least `millis` of virtual time has passed.
+    pub fn run(&mut self, millis: u64) {
+        let until = self.time + millis;
+        while self.time < until {
+            self.step();
+        }
+    }
+
+    /// Run steps until `done` holds, for at most `millis` of virtual time. Returns the virtual
+    /// time it took, or `None` if `done` never held.
+    pub fn run_until<F>(&mut self, millis: u64, done: F) -> Option<u64>
+    where
+        F: Fn(&Cluster) -> bool,
+    {
+        let start = self.time;
+        while self.time < start + millis {
+            self.step();
+            if done(self) {
+                return Some(self.time - start);
+            }
+        }
+        None
+    }
+
+    /// Advance the clock by one step, then let every running node ping its seeds, gossip,
+    /// receive what was sent to it and detect failures.
+    pub fn step(&mut self) {
+        self.time += self.step;
+        let time = self.time;
+
+        let mut outbox: Vec<(usize, Addr, Message)> = vec![];
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if !node.up {
+                continue;
+            }
+            node.agent.tick(time);
+            let ping = Message::Ping(node.agent.info());
+            for to in node.agent.ping() {
+                outbox.push((i, to, ping.clone()));
+            }
+            if node.agent.is_ready() {
+                for (to, message) in node.agent.gossip(time) {
+                    outbox.push((i, to, message));
+                }
+            }
+        }
+
+        for (from, to, message) in outbox {
+            if self.nodes[from].drop > 0 {
+                self.nodes[from].drop -= 1;
+                continue;
+            }
+            let j = match self.index(to) {
+                Some(j) if self.nodes[j].up && self.connected(from, j) => j,
+                _ => continue,
+            };
+            let sender = self.addr(from);
+            if let Some(socket) = self.nodes[from].socket.as_ref() {
+                let _ = socket.send_to(&message.bytes(), to.addr());
+                continue;
+            }
+            let node = &mut self.nodes[j];
+            let events = node.agent.accept_from(sender, &message, time);
+            node.events.extend(events);
+        }
+
+        let mut buf = [0_u8; MAX_DATAGRAM];
+        for node in self.nodes.iter_mut().filter(|node| node.up) {
+            let socket = match node.socket.as_ref() {
+                Some(socket) => socket,
+                None => continue,
+            };
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if let Some(message) = Message::parse(&buf[..len]) {
+                    let events = node.agent.accept_from(from.into(), &message, time);
+                    node.events.extend(events);
+                }
+            }
+        }
+
+        for node in self.nodes.iter_mut().filter(|node| node.up) {
+            let events = node.agent.detect(time);
+            node.events.extend(events);
+        }
+    }
+
+    fn index(&self, addr: Addr) -> Option<usize> {
+        self.nodes.iter().position(|node| node.addr == addr)
+    }
+
+    fn connected(&self, i: usize, j: usize) -> bool {
+        self.nodes[i].group == self.nodes[j].group
+            && !self
+                .blackholes
+                .iter()
+                .any(|&link| link == (i, j) || link == (j, i))
+    }
+
+    /// Running nodes connected to node `i` through running nodes, `i` included.
+    fn reachable(&self, i: usize) -> Vec<usize> {
+        let mut reached = vec![i];
+        let mut next = 0;
+        while next < reached.len() {
+            let k = reached[next];
+            next += 1;
+            for j in 0..self.nodes.len() {
+                if self.nodes[j].up && !reached.contains(&j) && self.connected(k, j) {
+                    reached.push(j);
+                }
+            }
+        }
+        reached
+    }
+}
+
+fn addr(i: usize) -> Addr {
+    Addr::new(0x7f000001, BASE_PORT + i as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PING_CUTOFF: u64 = 1000;
+    const FAIL_CUTOFF: u64 = 5000;
+
+    #[test]
+    fn test_crash_and_recover() {
+        let mut cluster = Cluster::new(4, PING_CUTOFF, FAIL_CUTOFF);
+        cluster.run(1000);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(3).len(), 4);
+
+        cluster.crash(3);
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        let gone = cluster.addr(3);
+        assert!(cluster
+            .events(0)
+            .iter()
+            .any(|e| matches!(e, Event::Remove(record) if record.addr() == gone)));
+
+        cluster.recover(3);
+        cluster.run(PING_CUTOFF + FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert!(cluster
+            .events(1)
+            .iter()
+            .any(|e| matches!(e, Event::Rejoin { current, .. } if current.addr() == gone)));
+    }
+
+    #[test]
+    fn test_udp() {
+        let mut cluster = Cluster::udp(3, PING_CUTOFF, FAIL_CUTOFF).unwrap();
+        assert!(cluster
+            .run_until(FAIL_CUTOFF, Cluster::is_converged)
+            .is_some());
+        assert_eq!(cluster.members(1).len(), 3);
+
+        cluster.crash(2);
+        let limit = PING_CUTOFF + 2 * FAIL_CUTOFF;
+        assert!(cluster.run_until(limit, Cluster::is_converged).is_some());
+        assert_eq!(cluster.members(0).len(), 2);
+
+        cluster.recover(2);
+        let limit = PING_CUTOFF + FAIL_CUTOFF;
+        assert!(cluster.run_until(limit, Cluster::is_converged).is_some());
+        assert_eq!(cluster.members(0).len(), 3);
+    }
+
+    #[test]
+    fn test_formation_is_not_churn() {
+        let mut cluster = Cluster::new(11, PING_CUTOFF, FAIL_CUTOFF)
+            .configure(|agent| agent.with_churn_detection(10, 2));
+        cluster.run(PING_CUTOFF + FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert!((0..11).all(|i| !cluster
+            .events(i)
+            .iter()
+            .any(|e| matches!(e, Event::HighChurn { .. }))));
+    }
+
+    #[test]
+    fn test_partition_and_heal() {
+        let mut cluster = Cluster::new(4, PING_CUTOFF, FAIL_CUTOFF)
+            .configure(|agent| agent.with_heal_detection(2));
+        cluster.run(1000);
+        assert!(cluster.is_converged());
+
+        cluster.partition(&[&[0, 1], &[2, 3]]);
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(2), vec![cluster.addr(2), cluster.addr(3)]);
+
+        cluster.heal();
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(0).len(), 4);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut cluster = Cluster::new(3, PING_CUTOFF, FAIL_CUTOFF);
+        cluster.run(1000);
+        assert!(cluster.is_converged());
+
+        let start = cluster.time();
+        cluster.advance(PING_CUTOFF + FAIL_CUTOFF);
+        assert_eq!(cluster.time(), start + PING_CUTOFF + FAIL_CUTOFF);
+        cluster.step();
+        assert!(cluster
+            .events(0)
+            .iter()
+            .any(|e| matches!(e, Event::Remove(_))));
+
+        cluster.run(PING_CUTOFF + FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(0).len(), 3);
+    }
+
+    #[test]
+    fn test_failure_injection() {
+        let mut cluster = Cluster::new(3, PING_CUTOFF, FAIL_CUTOFF);
+        cluster.run(1000);
+        let (a, b) = (cluster.addr(1), cluster.addr(2));
+
+        cluster.suspect(1, 2);
+        assert_eq!(cluster.members(1), vec![cluster.addr(0), a]);
+        cluster.run(PING_CUTOFF);
+        assert!(cluster.is_converged());
+        assert!(cluster
+            .events(1)
+            .iter()
+            .any(|e| matches!(e, Event::Rejoin { current, .. } if current.addr() == b)));
+
+        cluster.blackhole(1, 2);
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(1).len(), 3);
+        cluster.blackhole(0, 2);
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(2), vec![b]);
+        cluster.heal();
+        cluster.run(PING_CUTOFF + FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+        assert_eq!(cluster.members(2).len(), 3);
+
+        cluster.drop_next(2, usize::MAX);
+        cluster.run(PING_CUTOFF + 2 * FAIL_CUTOFF);
+        assert_eq!(cluster.members(0), vec![cluster.addr(0), a]);
+        assert_eq!(cluster.members(2), vec![b]);
+        cluster.drop_next(2, 0);
+        cluster.run(PING_CUTOFF + FAIL_CUTOFF);
+        assert!(cluster.is_converged());
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Debug;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::agent::Addr;
+
+/// Decides which peers this node gossips to in a round.
+pub trait Topology: Debug + Send {
+    /// Pick destinations among `peers`: live, recently heard peers ordered by address.
+    fn select(&mut self, this: Addr, peers: &[Addr]) -> Vec<Addr>;
+}
+
+/// Every live peer, every round.
+#[derive(Debug, Default)]
+pub struct FullMesh;
+
+impl Topology for FullMesh {
+    fn select(&mut self, _this: Addr, peers: &[Addr]) -> Vec<Addr> {
+        peers.to_vec()
+    }
+}
+
+/// A fresh random subset of `fanout` peers each round.
+#[derive(Debug)]
+pub struct RandomFanout {
+    fanout: usize,
+    rng: StdRng,
+}
+
+impl RandomFanout {
+    pub fn new(fanout: usize) -> Self {
+        Self::seeded(fanout, rand::random())
+    }
+
+    pub fn seeded(fanout: usize, seed: u64) -> Self {
+        Self {
+            fanout,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Topology for RandomFanout {
+    fn select(&mut self, _this: Addr, peers: &[Addr]) -> Vec<Addr> {
+        peers
+            .choose_multiple(&mut self.rng, self.fanout)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The `successors` next peers on the address ring after this node, plus `shortcuts` random
+/// others, so information travels around the ring and jumps across it.
+#[derive(Debug)]
+pub struct RingShortcuts {
+    successors: usize,
+    shortcuts: usize,
+    rng: StdRng,
+}
+
+impl RingShortcuts {
+    pub fn new(successors: usize, shortcuts: usize) -> Self {
+        Self::seeded(successors, shortcuts, rand::random())
+    }
+
+    pub fn seeded(successors: usize, shortcuts: usize, seed: u64) -> Self {
+        Self {
+            successors,
+            shortcuts,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Topology for RingShortcuts {
+    fn select(&mut self, this: Addr, peers: &[Addr]) -> Vec<Addr> {
+        let start = peers.iter().position(|addr| addr > &this).unwrap_or(0);
+        let mut ring = peers.to_vec();
+        ring.rotate_left(start);
+        let rest = ring.split_off(self.successors.min(ring.len()));
+        ring.extend(rest.choose_multiple(&mut self.rng, self.shortcuts));
+        ring
+    }
+}
+
+/// A fixed list of neighbors, of which those currently live are selected.
+#[derive(Debug)]
+pub struct StaticNeighbors {
+    neighbors: Vec<Addr>,
+}
+
+impl StaticNeighbors {
+    pub fn new(neighbors: Vec<Addr>) -> Self {
+        Self { neighbors }
+    }
+}
+
+impl Topology for StaticNeighbors {
+    fn select(&mut self, _this: Addr, peers: &[Addr]) -> Vec<Addr> {
+        peers
+            .iter()
+            .filter(|addr| self.neighbors.contains(addr))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Addr {
+        Addr::new(1, port)
+    }
+
+    fn addrs(ports: &[u16]) -> Vec<Addr> {
+        ports.iter().map(|port| addr(*port)).collect()
+    }
+
+    #[test]
+    fn test_full_mesh() {
+        let peers = addrs(&[1, 2, 4]);
+        assert_eq!(FullMesh.select(addr(3), &peers), peers);
+    }
+
+    #[test]
+    fn test_random_fanout() {
+        let peers = addrs(&[1, 2, 4, 5, 6]);
+        let mut topology = RandomFanout::seeded(2, 42);
+        for _ in 0..10 {
+            let selected = topology.select(addr(3), &peers);
+            assert_eq!(selected.len(), 2);
+            assert_ne!(selected[0], selected[1]);
+            assert!(selected.iter().all(|addr| peers.contains(addr)));
+        }
+        assert_eq!(topology.select(addr(3), &peers[..1]), addrs(&[1]));
+    }
+
+    #[test]
+    fn test_ring_shortcuts() {
+        let peers = addrs(&[1, 2, 4, 5, 6]);
+        let mut topology = RingShortcuts::seeded(2, 1, 42);
+        let selected = topology.select(addr(3), &peers);
+        assert_eq!(&selected[..2], &addrs(&[4, 5])[..]);
+        assert_eq!(selected.len(), 3);
+        assert!(addrs(&[6, 1, 2]).contains(&selected[2]));
+
+        let selected = topology.select(addr(6), &addrs(&[1, 2, 4, 5]));
+        assert_eq!(&selected[..2], &addrs(&[1, 2])[..]);
+    }
+
+    #[test]
+    fn test_static_neighbors() {
+        let mut topology = StaticNeighbors::new(addrs(&[2, 7]));
+        assert_eq!(topology.select(addr(3), &addrs(&[1, 2, 4])), addrs(&[2]));
+    }
+}
@@ -0,0 +1,226 @@
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use gossip_peer::agent::Event;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The node's UDP socket. A fatal error closes it, and it is bound again with exponential
+/// backoff, while the agent keeps its state. Both transitions are reported as events.
+pub struct Transport {
+    port: u16,
+    read_timeout: Duration,
+    socket: Option<UdpSocket>,
+    backoff: Duration,
+    retry_at: Instant,
+    events: Vec<Event>,
+}
+
+impl Transport {
+    /// Port 0 binds a free port, which `port` then returns and rebinding reuses.
+    pub fn bind(port: u16, read_timeout: Duration) -> io::Result<Self> {
+        let socket = open(port, read_timeout)?;
+        let port = socket.local_addr()?.port();
+        Ok(Self {
+            port,
+            read_timeout,
+            socket: Some(socket),
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            events: Vec::new(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let result = self.socket()?.send_to(bytes, addr);
+        if let Err(e) = &result {
+            debug!("send to {} failed: {}", addr, e);
+            self.check(e);
+        }
+        result
+    }
+
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let result = self.socket()?.recv_from(buf);
+        match &result {
+            Ok(_) => self.backoff = MIN_BACKOFF,
+            Err(e) => self.check(e),
+        }
+        result
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        if let Some(socket) = self.socket.as_ref() {
+            if let Err(e) = socket.set_nonblocking(nonblocking) {
+                warn!("set nonblocking failed: {}", e);
+            }
+        }
+    }
+
+    /// Bind again if the socket is closed and the backoff has passed.
+    pub fn recover(&mut self, now: Instant) {
+        if self.socket.is_some() || now < self.retry_at {
+            return;
+        }
+        match open(self.port, self.read_timeout) {
+            Ok(socket) => {
+                info!("socket bound again at :{}", self.port);
+                self.socket = Some(socket);
+                self.events.push(Event::TransportUp);
+            }
+            Err(e) => {
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                self.retry_at = now + self.backoff;
+                warn!("rebind failed: {}, retrying in {:?}", e, self.backoff);
+            }
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn socket(&self) -> io::Result<&UdpSocket> {
+        self.socket
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "socket is down"))
+    }
+
+    fn check(&mut self, e: &io::Error) {
+        if !is_fatal(e) || self.socket.is_none() {
+            return;
+        }
+        warn!("socket failed: {}, rebinding in {:?}", e, self.backoff);
+        self.socket = None;
+        self.retry_at = Instant::now() + self.backoff;
+        self.events.push(Event::TransportDown);
+    }
+}
+
+fn open(port: u16, read_timeout: Duration) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+    socket.set_read_timeout(Some(read_timeout))?;
+    Ok(socket)
+}
+
+/// IPv4 addresses of this host's interfaces, for the agent to recognize itself in gossip.
+#[cfg(unix)]
+pub fn local_hosts() -> Vec<u32> {
+    let mut hosts = vec![];
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } == -1 {
+        warn!("getifaddrs failed: {}", io::Error::last_os_error());
+        return hosts;
+    }
+    let mut next = addrs;
+    while let Some(ifa) = unsafe { next.as_ref() } {
+        if let Some(addr) = unsafe { ifa.ifa_addr.as_ref() } {
+            if addr.sa_family as libc::c_int == libc::AF_INET {
+                let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                hosts.push(u32::from_be(addr.sin_addr.s_addr));
+            }
+        }
+        next = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    hosts
+}
+
+#[cfg(not(unix))]
+pub fn local_hosts() -> Vec<u32> {
+    vec![]
+}
+
+/// Errors meaning the socket is unusable, or the network under it is gone (interface down,
+/// local address removed, no route to any network), are fatal: binding again picks up the
+/// new state. Errors about a single destination or datagram (invalid address, host
+/// unreachable, refused, timed out) leave it open.
+#[cfg(unix)]
+fn is_fatal(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF)
+            | Some(libc::ENOTSOCK)
+            | Some(libc::ENETDOWN)
+            | Some(libc::EADDRNOTAVAIL)
+            | Some(libc::ENETUNREACH)
+    )
+}
+
+#[cfg(not(unix))]
+fn is_fatal(_: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_hosts() {
+        assert!(local_hosts().contains(&0x7f000001));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_is_fatal() {
+        for errno in [
+            libc::EBADF,
+            libc::ENOTSOCK,
+            libc::ENETDOWN,
+            libc::EADDRNOTAVAIL,
+            libc::ENETUNREACH,
+        ] {
+            assert!(is_fatal(&io::Error::from_raw_os_error(errno)), "{}", errno);
+        }
+        for errno in [
+            libc::EINVAL,
+            libc::EHOSTUNREACH,
+            libc::EACCES,
+            libc::EPERM,
+            libc::ECONNREFUSED,
+        ] {
+            assert!(!is_fatal(&io::Error::from_raw_os_error(errno)), "{}", errno);
+        }
+        assert!(!is_fatal(&io::Error::other("interface down")));
+    }
+
+    #[test]
+    fn test_send_to_port_zero() {
+        let mut transport = Transport::bind(0, Duration::from_millis(10)).unwrap();
+        let _ = transport.send_to(&[0], SocketAddr::from(([127, 0, 0, 1], 0)));
+        assert!(transport.take_events().is_empty());
+        assert!(transport.socket().is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_recover() {
+        let mut transport = Transport::bind(0, Duration::from_millis(10)).unwrap();
+        let mut buf = [0_u8; 16];
+        assert!(transport.recv_from(&mut buf).is_err());
+        assert!(transport.take_events().is_empty());
+
+        transport.check(&io::Error::from_raw_os_error(libc::EBADF));
+        assert_eq!(transport.take_events(), vec![Event::TransportDown]);
+        let err = transport.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(transport.take_events().is_empty());
+
+        transport.recover(Instant::now());
+        assert!(transport.take_events().is_empty());
+        transport.recover(Instant::now() + MIN_BACKOFF);
+        assert_eq!(transport.take_events(), vec![Event::TransportUp]);
+        assert!(transport.recv_from(&mut buf).is_err());
+        assert!(transport.take_events().is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+//! Wire encoding of messages. Every integer is big-endian (network order), fields are packed
+//! without padding, and a datagram carries exactly one message:
+//!
+//! ```text
+//! message    = code:u8 body
+//! ping       = 0 info
+//! list       = 1 count:u32 info{count}
+//! echo       = 2 nonce:u64
+//! echo reply = 3 nonce:u64
+//! params     = 4 ping_cutoff:u64 fail_cutoff:u64 time:u64
+//! info       = host:u32 port:u16 beat:u64
+//! ```
+//!
+//! Decoding fails on truncated input, on counts larger than the remaining bytes can hold and
+//! on unknown codes. Trailing bytes after a message are ignored, leaving room to append fields
+//! in a later layout without breaking older peers.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::agent::{Addr, Info, InfoList, Message};
+
+pub const PING: u8 = 0;
+pub const LIST: u8 = 1;
+pub const ECHO: u8 = 2;
+pub const ECHO_REPLY: u8 = 3;
+pub const PARAMS: u8 = 4;
+
+pub const CODE_LEN: usize = 1;
+pub const COUNT_LEN: usize = 4;
+pub const NONCE_LEN: usize = 8;
+pub const CUTOFF_LEN: usize = 8;
+pub const TIME_LEN: usize = 8;
+pub const ADDR_LEN: usize = 4 + 2;
+pub const INFO_LEN: usize = ADDR_LEN + 8;
+
+pub trait Encode {
+    fn encode(&self, buf: &mut BytesMut);
+}
+
+pub trait Decode: Sized {
+    fn decode(buf: &mut Bytes) -> Option<Self>;
+}
+
+impl Encode for Addr {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.host());
+        buf.put_u16(self.port());
+    }
+}
+
+impl Decode for Addr {
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        if buf.remaining() < ADDR_LEN {
+            return None;
+        }
+        let host = buf.get_u32();
+        let port = buf.get_u16();
+        Some(Addr::new(host, port))
+    }
+}
+
+impl Encode for Info {
+    fn encode(&self, buf: &mut BytesMut) {
+        self.addr().encode(buf);
+        buf.put_u64(self.beat());
+    }
+}
+
+impl Decode for Info {
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        if buf.remaining() < INFO_LEN {
+            return None;
+        }
+        let addr = Addr::decode(buf)?;
+        let beat = buf.get_u64();
+        Some(Info::new(addr, beat))
+    }
+}
+
+impl Encode for Message {
+    fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Message::Ping(info) => {
+                buf.put_u8(PING);
+                info.encode(buf);
+            }
+            Message::List(list) => {
+                buf.put_u8(LIST);
+                buf.put_u32(list.len() as u32);
+                for info in list {
+                    info.encode(buf);
+                }
+            }
+            Message::Echo(nonce) => {
+                buf.put_u8(ECHO);
+                buf.put_u64(*nonce);
+            }
+            Message::EchoReply(nonce) => {
+                buf.put_u8(ECHO_REPLY);
+                buf.put_u64(*nonce);
+            }
+            Message::Params {
+                ping_cutoff,
+                fail_cutoff,
+                time,
+            } => {
+                buf.put_u8(PARAMS);
+                buf.put_u64(*ping_cutoff);
+                buf.put_u64(*fail_cutoff);
+                buf.put_u64(*time);
+            }
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode(buf: &mut Bytes) -> Option<Self> {
+        if buf.remaining() < CODE_LEN {
+            return None;
+        }
+        match buf.get_u8() {
+            PING => Info::decode(buf).map(Message::Ping),
+            LIST => {
+                if buf.remaining() < COUNT_LEN {
+                    return None;
+                }
+                let count = buf.get_u32() as usize;
+                if count > buf.remaining() / INFO_LEN {
+                    return None;
+                }
+                let mut list = InfoList::with_capacity(count);
+                for _ in 0..count {
+                    list.push(Info::decode(buf)?);
+                }
+                Some(Message::List(list))
+            }
+            ECHO if buf.remaining() >= NONCE_LEN => Some(Message::Echo(buf.get_u64())),
+            ECHO_REPLY if buf.remaining() >= NONCE_LEN => Some(Message::EchoReply(buf.get_u64())),
+            PARAMS if buf.remaining() >= 2 * CUTOFF_LEN + TIME_LEN => Some(Message::Params {
+                ping_cutoff: buf.get_u64(),
+                fail_cutoff: buf.get_u64(),
+                time: buf.get_u64(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Encoded length of a message.
+pub fn len(message: &Message) -> usize {
+    CODE_LEN
+        + match message {
+            Message::Ping(_) => INFO_LEN,
+            Message::List(list) => COUNT_LEN + INFO_LEN * list.len(),
+            Message::Echo(_) | Message::EchoReply(_) => NONCE_LEN,
+            Message::Params { .. } => 2 * CUTOFF_LEN + TIME_LEN,
+        }
+}
+
+/// Field types of the layout description below.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Type {
+    U16,
+    U32,
+    U64,
+    /// One of `STRUCTS`, by name.
+    Struct(&'static str),
+    /// A struct repeated as many times as an earlier field says.
+    Repeated(&'static str, &'static str),
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: Type,
+}
+
+/// Layout of a message (with its code) or of a struct embedded in messages, in field order.
+#[derive(Debug)]
+pub struct Layout {
+    pub name: &'static str,
+    pub code: Option<u8>,
+    pub fields: &'static [Field],
+}
+
+pub const STRUCTS: &[Layout] = &[Layout {
+    name: "info",
+    code: None,
+    fields: &[
+        Field {
+            name: "host",
+            ty: Type::U32,
+        },
+        Field {
+            name: "port",
+            ty: Type::U16,
+        },
+        Field {
+            name: "beat",
+            ty: Type::U64,
+        },
+    ],
+}];
+
+/// Every message, named as `Message::kind` names it. Each starts with its code as a u8.
+pub const MESSAGES: &[Layout] = &[
+    Layout {
+        name: "ping",
+        code: Some(PING),
+        fields: &[Field {
+            name: "info",
+            ty: Type::Struct("info"),
+        }],
+    },
+    Layout {
+        name: "list",
+        code: Some(LIST),
+        fields: &[
+            Field {
+                name: "count",
+                ty: Type::U32,
+            },
+            Field {
+                name: "infos",
+                ty: Type::Repeated("info", "count"),
+            },
+        ],
+    },
+    Layout {
+        name: "echo",
+        code: Some(ECHO),
+        fields: &[Field {
+            name: "nonce",
+            ty: Type::U64,
+        }],
+    },
+    Layout {
+        name: "echo_reply",
+        code: Some(ECHO_REPLY),
+        fields: &[Field {
+            name: "nonce",
+            ty: Type::U64,
+        }],
+    },
+    Layout {
+        name: "params",
+        code: Some(PARAMS),
+        fields: &[
+            Field {
+                name: "ping_cutoff",
+                ty: Type::U64,
+            },
+            Field {
+                name: "fail_cutoff",
+                ty: Type::U64,
+            },
+            Field {
+                name: "time",
+                ty: Type::U64,
+            },
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smallvec::smallvec;
+
+    fn encode(message: &Message) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        message.encode(&mut buf);
+        buf.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Message> {
+        Message::decode(&mut Bytes::copy_from_slice(bytes))
+    }
+
+    fn samples() -> Vec<Message> {
+        let addrs = [
+            Addr::new(0, 0),
+            Addr::new(0x7f000001, 12000),
+            Addr::new(u32::MAX, u16::MAX),
+        ];
+        let infos: InfoList = addrs
+            .iter()
+            .flat_map(|addr| [0, 1, u64::MAX].map(|beat| Info::new(*addr, beat)))
+            .collect();
+        let mut messages: Vec<Message> = infos.iter().cloned().map(Message::Ping).collect();
+        messages.push(Message::List(smallvec![]));
+        messages.push(Message::List(infos));
+        for nonce in [0, 42, u64::MAX] {
+            messages.push(Message::Echo(nonce));
+            messages.push(Message::EchoReply(nonce));
+        }
+        messages.push(Message::Params {
+            ping_cutoff: 1000,
+            fail_cutoff: u64::MAX,
+            time: 1577836800000,
+        });
+        messages
+    }
+
+    #[test]
+    fn test_layout() {
+        let info = Info::new(Addr::new(0x0a000001, 0x1f40), 0x0102030405060708);
+        let info_bytes = [10, 0, 0, 1, 0x1f, 0x40, 1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert_eq!(
+            encode(&Message::Ping(info)),
+            [&[0][..], &info_bytes].concat()
+        );
+        assert_eq!(
+            encode(&Message::List(smallvec![info, info])),
+            [&[1, 0, 0, 0, 2][..], &info_bytes, &info_bytes].concat()
+        );
+        assert_eq!(encode(&Message::Echo(0x0102)), [2, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(
+            encode(&Message::EchoReply(0x0102)),
+            [3, 0, 0, 0, 0, 0, 0, 1, 2]
+        );
+        assert_eq!(
+            encode(&Message::Params {
+                ping_cutoff: 0x0102,
+                fail_cutoff: 0x0304,
+                time: 0x0506,
+            }),
+            [4, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 3, 4, 0, 0, 0, 0, 0, 0, 5, 6]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for message in samples() {
+            let bytes = encode(&message);
+            assert_eq!(bytes.len(), len(&message), "{:?}", message);
+            assert_eq!(decode(&bytes), Some(message.clone()));
+
+            for end in 0..bytes.len() {
+                assert_eq!(decode(&bytes[..end]), None, "{:?} cut at {}", message, end);
+            }
+
+            let mut padded = bytes.clone();
+            padded.extend_from_slice(&[0xff; 3]);
+            assert_eq!(decode(&padded), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_unknown_code() {
+        for code in PARAMS + 1..=u8::MAX {
+            assert_eq!(decode(&[code; 32]), None);
+        }
+    }
+
+    fn layout_len(layout: &Layout, count: usize) -> usize {
+        layout
+            .fields
+            .iter()
+            .map(|field| match field.ty {
+                Type::U16 => 2,
+                Type::U32 => 4,
+                Type::U64 => 8,
+                Type::Struct(name) => {
+                    layout_len(STRUCTS.iter().find(|s| s.name == name).unwrap(), 0)
+                }
+                Type::Repeated(name, _) => {
+                    count * layout_len(STRUCTS.iter().find(|s| s.name == name).unwrap(), 0)
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_schema() {
+        assert_eq!(layout_len(&STRUCTS[0], 0), INFO_LEN);
+        for message in samples() {
+            let bytes = encode(&message);
+            let layout = MESSAGES
+                .iter()
+                .find(|layout| layout.name == message.kind())
+                .unwrap();
+            assert_eq!(layout.code, Some(bytes[0]));
+            let count = match &message {
+                Message::List(list) => list.len(),
+                _ => 0,
+            };
+            assert_eq!(CODE_LEN + layout_len(layout, count), bytes.len());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Counts heap allocations made by a gossip round on the calling thread.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use gossip_peer::agent::{Addr, Agent, Info, Message, Record};
+use gossip_peer::topology::RandomFanout;
+
+struct Counting;
+
+thread_local! {
+    static COUNT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        COUNT.with(|count| count.set(count.get().map(|n| n + 1)));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const TIME: u64 = 1_000_000_000;
+
+fn agent(n: usize, fanout: usize) -> Agent {
+    let addr = |i: usize| Addr::new(0x0a000000 + i as u32, 12000);
+    let this = Record::new(addr(0), TIME, TIME);
+    let mut agent = Agent::new(this, vec![], 1000, 5000)
+        .with_topology(RandomFanout::seeded(fanout, 42));
+    let list = (1..n).map(|i| Info::new(addr(i), TIME)).collect();
+    agent.accept(&Message::List(list), TIME);
+    // the first round allocates per-peer stats entries
+    agent.gossip(TIME);
+    agent
+}
+
+fn allocations(agent: &mut Agent) -> usize {
+    COUNT.with(|count| count.set(Some(0)));
+    let messages = agent.gossip(TIME);
+    let count = COUNT.with(|count| count.replace(None)).unwrap();
+    drop(messages);
+    count
+}
+
+#[test]
+fn test_gossip_allocations() {
+    // lists of a small cluster stay inline, only the round's bookkeeping allocates
+    let small = allocations(&mut agent(8, 3));
+    assert!(small <= 4, "{} allocations for a small cluster", small);
+
+    let medium = allocations(&mut agent(30, 3));
+    let large = allocations(&mut agent(70, 3));
+    assert_eq!(medium, large, "allocations grow with cluster size");
+
+    // a payload per destination, occasionally a stats entry for a peer not gossiped to before,
+    // and a handful shared by the whole round
+    assert!(large <= 4 + 2 * 3, "{} allocations for fanout 3", large);
+    let wide = allocations(&mut agent(70, 9));
+    assert!(wide <= 4 + 2 * 9, "{} allocations for fanout 9", wide);
+}
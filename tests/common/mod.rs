@@ -0,0 +1,137 @@
+//! Runs the `gossip-peer` binary on a free port and follows its log.
+
+// Each test crate uses a part of it
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Default)]
+struct Log {
+    lines: Mutex<Vec<String>>,
+    added: Condvar,
+}
+
+pub struct Node {
+    pub child: Child,
+    pub port: u16,
+    log: Arc<Log>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    /// Start the binary at 127.0.0.1 on a port it picks, with `args` (options or seeds) after
+    /// the port, and wait until it is listening.
+    pub fn spawn(args: &[String]) -> Node {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gossip-peer"))
+            .env("RUST_LOG", "info")
+            .args(["--host", "127.0.0.1", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start gossip-peer");
+
+        let log = Arc::new(Log::default());
+        let stderr = child.stderr.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let streams: Vec<Box<dyn Read + Send>> = vec![Box::new(stderr), Box::new(stdout)];
+        let readers = streams
+            .into_iter()
+            .map(|stream| {
+                let log = log.clone();
+                thread::spawn(move || {
+                    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                        log.lines.lock().unwrap().push(line);
+                        log.added.notify_all();
+                    }
+                })
+            })
+            .collect();
+
+        let mut node = Node {
+            child,
+            port: 0,
+            log,
+            readers,
+        };
+        let listening = node
+            .wait_for(|line| line.contains("listening at :"))
+            .expect("gossip-peer did not start listening");
+        node.port = listening
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.trim().parse().ok())
+            .expect("no port in the listening line");
+        node
+    }
+
+    pub fn addr(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// The first log line `f` accepts, waiting up to `TIMEOUT` for it to be written.
+    pub fn wait_for<F: Fn(&str) -> bool>(&self, f: F) -> Option<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut lines = self.log.lines.lock().unwrap();
+        let mut seen = 0;
+        loop {
+            if let Some(line) = lines[seen..].iter().find(|line| f(line)) {
+                return Some(line.clone());
+            }
+            seen = lines.len();
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            lines = self
+                .log
+                .added
+                .wait_timeout(lines, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Log lines so far that `f` accepts.
+    pub fn count<F: Fn(&str) -> bool>(&self, f: F) -> usize {
+        self.log
+            .lines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| f(line))
+            .count()
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        self.child.try_wait().unwrap().is_none()
+    }
+
+    /// Shut the node down gracefully and wait until it has exited and its log is complete.
+    pub fn terminate(&mut self) -> ExitStatus {
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        #[cfg(not(unix))]
+        let _ = self.child.kill();
+        let status = self.child.wait().expect("failed to wait for gossip-peer");
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+        status
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,145 @@
+//! Drives a single `gossip-peer` process from a scripted fake peer over a real socket.
+
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use gossip_peer::agent::Message;
+
+mod common;
+
+use common::{Node, TIMEOUT};
+
+struct Harness {
+    node: Node,
+    socket: UdpSocket,
+    peer: String,
+    port: u16,
+    beat: u64,
+}
+
+impl Harness {
+    fn start() -> Harness {
+        let node = Node::spawn(&[]);
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).expect("bind failed");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let port = socket.local_addr().unwrap().port();
+        Harness {
+            peer: node.addr(),
+            node,
+            socket,
+            port,
+            beat: 0,
+        }
+    }
+
+    fn send(&self, bytes: &[u8]) {
+        self.socket.send_to(bytes, &self.peer).unwrap();
+    }
+
+    fn ping(&self, beat: u64) -> Vec<u8> {
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&0_u32.to_be_bytes());
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes.extend_from_slice(&beat.to_be_bytes());
+        bytes
+    }
+
+    /// Keep heartbeating until the peer gossips a List back, proving it is alive and serving.
+    fn expect_list(&mut self) -> Vec<u8> {
+        let start = Instant::now();
+        let mut buf = [0_u8; 1024];
+        while start.elapsed() < TIMEOUT {
+            self.beat += 1;
+            self.send(&self.ping(self.beat));
+            if let Ok((len, _)) = self.socket.recv_from(&mut buf) {
+                if let Some(Message::List(_)) = Message::parse(&buf[..len]) {
+                    return buf[..len].to_vec();
+                }
+            }
+        }
+        panic!("no List received from {}", self.peer);
+    }
+
+    fn is_running(&mut self) -> bool {
+        self.node.is_running()
+    }
+
+    fn count_events(&self, kind: &str) -> usize {
+        let event = format!("event: {}", kind);
+        let addr = format!("addr: 127.0.0.1:{},", self.port);
+        self.node
+            .count(|line| line.contains(&event) && line.contains(&addr))
+    }
+}
+
+#[test]
+fn test_join_is_answered_with_list() {
+    let mut h = Harness::start();
+    let list = h.expect_list();
+    assert_eq!(list[0], 1);
+    assert_eq!(u32::from_be_bytes([list[1], list[2], list[3], list[4]]), 1);
+    assert_eq!(&list[5..9], &[127, 0, 0, 1]);
+    assert_eq!(u16::from_be_bytes([list[9], list[10]]), h.node.port);
+}
+
+#[test]
+fn test_truncated_packets() {
+    let mut h = Harness::start();
+    h.expect_list();
+
+    let ping = h.ping(1);
+    for len in 0..ping.len() {
+        h.send(&ping[..len]);
+    }
+    h.send(&[1]);
+    h.send(&[1, 0, 0, 0, 2, 0, 0, 0, 0, 1, 1]);
+
+    assert!(h.is_running());
+    h.expect_list();
+}
+
+#[test]
+fn test_huge_counts_and_unknown_codes() {
+    let mut h = Harness::start();
+    h.expect_list();
+
+    h.send(&[1, 0xff, 0xff, 0xff, 0xff]);
+    h.send(&[1, 0x7f, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+    for code in 4..=255_u8 {
+        h.send(&[code, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    assert!(h.is_running());
+    h.expect_list();
+}
+
+#[test]
+fn test_replayed_join() {
+    let mut h = Harness::start();
+    h.expect_list();
+
+    let ping = h.ping(h.beat);
+    for _ in 0..10 {
+        h.send(&ping);
+    }
+
+    assert!(h.is_running());
+    h.expect_list();
+    assert_eq!(h.count_events("Append"), 1);
+    assert_eq!(h.count_events("Rejoin"), 0);
+}
+
+#[test]
+fn test_echo_without_join() {
+    let mut h = Harness::start();
+
+    h.send(&Message::Echo(42).bytes());
+    let mut buf = [0_u8; 1024];
+    let (len, _) = h.socket.recv_from(&mut buf).expect("no echo reply");
+    assert_eq!(Message::parse(&buf[..len]), Some(Message::EchoReply(42)));
+
+    h.node.terminate();
+    assert_eq!(h.count_events("Append"), 0);
+}
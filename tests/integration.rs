@@ -0,0 +1,49 @@
+//! Runs several `gossip-peer` processes on localhost and checks their logs.
+//!
+//! `cargo test --features integration --test integration`
+#![cfg(all(unix, feature = "integration"))]
+
+mod common;
+
+use common::Node;
+
+fn spawn(seeds: &[&Node]) -> Node {
+    let seeds: Vec<String> = seeds.iter().map(|seed| seed.addr()).collect();
+    Node::spawn(&seeds)
+}
+
+fn wait_event(node: &Node, kind: &str, peer: &Node) -> bool {
+    let event = format!("event: {}", kind);
+    let addr = format!("addr: {},", peer.addr());
+    node.wait_for(|line| line.contains(&event) && line.contains(&addr))
+        .is_some()
+}
+
+#[test]
+fn test_cluster() {
+    let a = spawn(&[]);
+    let mut b = spawn(&[&a]);
+    let mut c = spawn(&[&a]);
+
+    // convergence: everyone learns about everyone else
+    for (x, y) in &[(&a, &b), (&a, &c), (&b, &a), (&b, &c), (&c, &a), (&c, &b)] {
+        assert!(
+            wait_event(x, "Append", y),
+            "{} did not see {}",
+            x.addr(),
+            y.addr()
+        );
+    }
+
+    // failure detection: a crashed peer is removed by the others
+    c.child.kill().unwrap();
+    c.child.wait().unwrap();
+    assert!(wait_event(&a, "Remove", &c));
+    assert!(wait_event(&b, "Remove", &c));
+
+    // leave: a peer shut down with SIGTERM exits cleanly and is removed
+    let status = b.terminate();
+    assert!(status.success(), "exit status: {}", status);
+    assert!(b.wait_for(|line| line.starts_with("up: ")).is_some());
+    assert!(wait_event(&a, "Remove", &b));
+}